@@ -5,6 +5,7 @@ use crate::settings::record_dynamic_config;
 use crate::types::*;
 use crate::util::file_path_to_uri;
 use crate::util::*;
+use crate::workspace::workspace_folder;
 use indoc::formatdoc;
 use itertools::Itertools;
 use lsp_types::notification::*;
@@ -74,7 +75,7 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                                     ResourceOperationKind::Delete,
                                     ResourceOperationKind::Rename,
                                 ]),
-                                failure_handling: Some(FailureHandlingKind::Abort),
+                                failure_handling: Some(FailureHandlingKind::Transactional),
                                 normalizes_line_endings: Some(false),
                                 change_annotation_support: Some(
                                     ChangeAnnotationWorkspaceEditClientCapabilities {
@@ -100,7 +101,7 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                             execute_command: Some(DynamicRegistrationClientCapabilities {
                                 dynamic_registration: Some(false),
                             }),
-                            workspace_folders: Some(false),
+                            workspace_folders: Some(true),
                             configuration: Some(true),
                             semantic_tokens: enum_primitive::Option::Some(
                                 SemanticTokensWorkspaceClientCapabilities {
@@ -110,33 +111,45 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                             code_lens: Some(CodeLensWorkspaceClientCapabilities {
                                 refresh_support: Some(true),
                             }),
-                            file_operations: None,
+                            file_operations: Some(WorkspaceFileOperationsClientCapabilities {
+                                dynamic_registration: Some(true),
+                                did_create: Some(true),
+                                will_create: Some(true),
+                                did_rename: Some(true),
+                                will_rename: Some(true),
+                                did_delete: Some(true),
+                                will_delete: Some(true),
+                            }),
                             inline_value: None,
                             inlay_hint: Some(InlayHintWorkspaceClientCapabilities {
                                 refresh_support: Some(true),
                             }),
-                            diagnostic: None,
+                            diagnostic: Some(DiagnosticWorkspaceClientCapabilities {
+                                refresh_support: Some(true),
+                            }),
                         }),
                         text_document: Some(TextDocumentClientCapabilities {
                             synchronization: Some(TextDocumentSyncClientCapabilities {
                                 dynamic_registration: Some(false),
-                                will_save: Some(false),
-                                will_save_wait_until: Some(false),
+                                will_save: Some(true),
+                                will_save_wait_until: Some(true),
                                 did_save: Some(true),
                             }),
                             completion: Some(CompletionClientCapabilities {
                                 dynamic_registration: Some(false),
                                 completion_item: Some(CompletionItemCapability {
                                     snippet_support: Some(ctx.config.snippet_support),
-                                    commit_characters_support: Some(false),
+                                    commit_characters_support: Some(true),
                                     documentation_format: Some(vec![
                                         MarkupKind::Markdown,
                                         MarkupKind::PlainText,
                                     ]),
-                                    deprecated_support: Some(false),
+                                    deprecated_support: Some(true),
                                     preselect_support: Some(false),
-                                    tag_support: None,
-                                    insert_replace_support: None,
+                                    tag_support: Some(TagSupport {
+                                        value_set: vec![CompletionItemTag::DEPRECATED],
+                                    }),
+                                    insert_replace_support: Some(true),
                                     resolve_support: Some(CompletionItemCapabilityResolveSupport {
                                         properties: vec![
                                             "additionalTextEdits".to_string(),
@@ -145,7 +158,7 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                                         ],
                                     }),
                                     insert_text_mode_support: None,
-                                    label_details_support: None,
+                                    label_details_support: Some(true),
                                 }),
                                 completion_item_kind: Some(CompletionItemKindCapability {
                                     value_set: Some(vec![
@@ -176,9 +189,11 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                                         CompletionItemKind::TYPE_PARAMETER,
                                     ]),
                                 }),
-                                context_support: Some(false),
+                                context_support: Some(true),
                                 insert_text_mode: None,
-                                completion_list: None,
+                                completion_list: Some(CompletionListCapability {
+                                    item_defaults: Some(vec!["commitCharacters".to_string()]),
+                                }),
                             }),
                             hover: Some(HoverClientCapabilities {
                                 dynamic_registration: Some(false),
@@ -190,13 +205,16 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                             signature_help: Some(SignatureHelpClientCapabilities {
                                 dynamic_registration: Some(false),
                                 signature_information: Some(SignatureInformationSettings {
-                                    documentation_format: Some(vec![MarkupKind::PlainText]),
+                                    documentation_format: Some(vec![
+                                        MarkupKind::Markdown,
+                                        MarkupKind::PlainText,
+                                    ]),
                                     parameter_information: Some(ParameterInformationSettings {
-                                        label_offset_support: Some(false),
+                                        label_offset_support: Some(true),
                                     }),
-                                    active_parameter_support: None,
+                                    active_parameter_support: Some(true),
                                 }),
-                                context_support: Some(false),
+                                context_support: Some(true),
                             }),
                             references: Some(DynamicRegistrationClientCapabilities {
                                 dynamic_registration: Some(false),
@@ -267,16 +285,18 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                             }),
                             document_link: Some(DocumentLinkClientCapabilities {
                                 dynamic_registration: Some(false),
-                                tooltip_support: Some(false),
+                                tooltip_support: Some(true),
                             }),
                             color_provider: Some(DynamicRegistrationClientCapabilities {
                                 dynamic_registration: Some(false),
                             }),
                             rename: Some(RenameClientCapabilities {
                                 dynamic_registration: Some(false),
-                                prepare_support: Some(false),
-                                prepare_support_default_behavior: None,
-                                honors_change_annotations: None,
+                                prepare_support: Some(true),
+                                prepare_support_default_behavior: Some(
+                                    PrepareSupportDefaultBehavior::IDENTIFIER,
+                                ),
+                                honors_change_annotations: Some(true),
                             }),
                             publish_diagnostics: Some(PublishDiagnosticsClientCapabilities {
                                 related_information: Some(true),
@@ -290,15 +310,31 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                                 code_description_support: None,
                                 data_support: None,
                             }),
-                            folding_range: None,
+                            folding_range: Some(FoldingRangeClientCapabilities {
+                                dynamic_registration: Some(false),
+                                range_limit: None,
+                                line_folding_only: Some(true),
+                                folding_range_kind: Some(FoldingRangeKindCapability {
+                                    value_set: Some(vec![
+                                        FoldingRangeKind::Comment,
+                                        FoldingRangeKind::Imports,
+                                        FoldingRangeKind::Region,
+                                    ]),
+                                }),
+                                folding_range: Some(FoldingRangeCapability {
+                                    collapsed_text: Some(false),
+                                }),
+                            }),
                             selection_range: Some(SelectionRangeClientCapabilities {
                                 dynamic_registration: None,
                             }),
                             semantic_tokens: Some(SemanticTokensClientCapabilities {
                                 dynamic_registration: Some(true),
                                 requests: SemanticTokensClientCapabilitiesRequests {
-                                    range: Some(false),
-                                    full: Some(SemanticTokensFullOptions::Bool(true)),
+                                    range: Some(true),
+                                    full: Some(SemanticTokensFullOptions::Delta {
+                                        delta: Some(true),
+                                    }),
                                 },
                                 token_types: semantic_tokens_config(&ctx.config, &meta)
                                     .iter()
@@ -316,23 +352,37 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                                     .into_iter()
                                     .collect(),
                                 formats: vec![TokenFormat::RELATIVE],
-                                overlapping_token_support: None,
-                                multiline_token_support: None,
+                                overlapping_token_support: Some(true),
+                                multiline_token_support: Some(true),
                                 augments_syntax_tokens: None,
                                 server_cancel_support: Some(true),
                             }),
-                            linked_editing_range: None,
+                            linked_editing_range: Some(LinkedEditingRangeClientCapabilities {
+                                dynamic_registration: Some(false),
+                            }),
                             call_hierarchy: Some(CallHierarchyClientCapabilities {
                                 dynamic_registration: Some(false),
                             }),
                             moniker: None,
                             inline_value: None,
-                            type_hierarchy: None,
+                            type_hierarchy: Some(TypeHierarchyClientCapabilities {
+                                dynamic_registration: Some(false),
+                            }),
                             inlay_hint: Some(InlayHintClientCapabilities {
                                 dynamic_registration: Some(false),
-                                resolve_support: None,
+                                resolve_support: Some(InlayHintResolveClientCapabilities {
+                                    properties: vec![
+                                        "tooltip".to_string(),
+                                        "label.tooltip".to_string(),
+                                        "label.location".to_string(),
+                                        "label.command".to_string(),
+                                    ],
+                                }),
+                            }),
+                            diagnostic: Some(DiagnosticClientCapabilities {
+                                dynamic_registration: Some(false),
+                                related_document_support: Some(true),
                             }),
-                            diagnostic: None,
                             inline_completion: None,
                         }),
                         window: Some(WindowClientCapabilities {
@@ -342,7 +392,7 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                                     additional_properties_support: Some(true),
                                 }),
                             }),
-                            show_document: None,
+                            show_document: Some(ShowDocumentClientCapabilities { support: true }),
                         }),
                         general: Some(GeneralClientCapabilities {
                             regular_expressions: Some(RegularExpressionsClientCapabilities {
@@ -392,10 +442,7 @@ pub fn initialize(meta: EditorMeta, ctx: &mut Context, servers: Vec<ServerId>) {
                     root_uri: Some(file_path_to_uri(&roots[0])),
                     root_path: Some(roots[0].clone()),
                     trace: Some(TraceValue::Off),
-                    workspace_folders: Some(vec![WorkspaceFolder {
-                        uri: file_path_to_uri(&roots[0]),
-                        name: roots[0].clone(),
-                    }]),
+                    workspace_folders: Some(roots.iter().map(workspace_folder).collect()),
                     client_info: Some(ClientInfo {
                         name: "kakoune-lsp".to_string(),
                         version: Some(env!("CARGO_PKG_VERSION").to_string()),
@@ -456,13 +503,18 @@ pub const CAPABILITY_CODE_ACTIONS_RESOLVE: &str = "lsp-code-actions-resolve";
 pub const CAPABILITY_CODE_LENS: &str = "lsp-code-lens";
 pub const CAPABILITY_COMPLETION: &str = "lsp-completion (hooked on InsertIdle)";
 pub const CAPABILITY_DEFINITION: &str = "lsp-definition";
+pub const CAPABILITY_DOCUMENT_COLOR: &str = "lsp-document-colors-enable, lsp-color-presentation";
+pub const CAPABILITY_DOCUMENT_DIAGNOSTIC: &str = "pull diagnostics (hooked on buffer changes)";
 pub const CAPABILITY_DOCUMENT_HIGHLIGHT: &str = "lsp-highlight-references";
+pub const CAPABILITY_DOCUMENT_LINK: &str = "lsp-document-links, lsp-open-link";
 pub const CAPABILITY_DOCUMENT_SYMBOL: &str = "lsp-document-symbol";
 pub const CAPABILITY_EXECUTE_COMMAND: &str = "lsp-execute-command";
+pub const CAPABILITY_FOLDING_RANGE: &str = "lsp-folding-ranges, lsp-fold-object";
 pub const CAPABILITY_FORMATTING: &str = "lsp-formatting";
 pub const CAPABILITY_HOVER: &str = "lsp-hover";
 pub const CAPABILITY_IMPLEMENTATION: &str = "lsp-implementation";
 pub const CAPABILITY_INLAY_HINTS: &str = "lsp-inlay-hints";
+pub const CAPABILITY_LINKED_EDITING_RANGE: &str = "lsp-linked-editing-range";
 pub const CAPABILITY_RANGE_FORMATTING: &str = "lsp-range-formatting";
 pub const CAPABILITY_REFERENCES: &str = "lsp-references";
 pub const CAPABILITY_RENAME: &str = "lsp-rename";
@@ -470,6 +522,7 @@ pub const CAPABILITY_SELECTION_RANGE: &str = "lsp-selection-range";
 pub const CAPABILITY_SEMANTIC_TOKENS: &str = "lsp-semantic-tokens";
 pub const CAPABILITY_SIGNATURE_HELP: &str = "lsp-signature-help";
 pub const CAPABILITY_TYPE_DEFINITION: &str = "lsp-type-definition";
+pub const CAPABILITY_WORKSPACE_DIAGNOSTIC: &str = "lsp-workspace-diagnostics";
 pub const CAPABILITY_WORKSPACE_SYMBOL: &str = "lsp-workspace-symbol";
 
 pub const CAPABILITY_TEXT_DOCUMENT_BUILD: &str = "texlab-build";
@@ -543,16 +596,28 @@ pub fn server_has_capability(
             Some(OneOf::Right(_)) => true,
             None => false,
         },
+        CAPABILITY_DOCUMENT_COLOR => match server_capabilities.color_provider {
+            Some(ColorProviderCapability::Simple(ok)) => ok,
+            Some(_) => true,
+            None => false,
+        },
+        CAPABILITY_DOCUMENT_DIAGNOSTIC => server_capabilities.diagnostic_provider.is_some(),
         CAPABILITY_DOCUMENT_HIGHLIGHT => match server_capabilities.document_highlight_provider {
             Some(OneOf::Left(ok)) => ok,
             Some(OneOf::Right(_)) => true,
             None => false,
         },
+        CAPABILITY_DOCUMENT_LINK => server_capabilities.document_link_provider.is_some(),
         CAPABILITY_DOCUMENT_SYMBOL => match server_capabilities.document_symbol_provider {
             Some(OneOf::Left(ok)) => ok,
             Some(OneOf::Right(_)) => true,
             None => false,
         },
+        CAPABILITY_FOLDING_RANGE => match server_capabilities.folding_range_provider {
+            Some(FoldingRangeProviderCapability::Simple(ok)) => ok,
+            Some(_) => true,
+            None => false,
+        },
         CAPABILITY_FORMATTING => match server_capabilities.document_formatting_provider {
             Some(OneOf::Left(ok)) => ok,
             Some(OneOf::Right(_)) => true,
@@ -573,6 +638,13 @@ pub fn server_has_capability(
             Some(OneOf::Right(_)) => true,
             None => false,
         },
+        CAPABILITY_LINKED_EDITING_RANGE => {
+            match server_capabilities.linked_editing_range_provider {
+                Some(LinkedEditingRangeServerCapabilities::Simple(ok)) => ok,
+                Some(_) => true,
+                None => false,
+            }
+        }
         CAPABILITY_RANGE_FORMATTING => match server_capabilities.document_range_formatting_provider
         {
             Some(OneOf::Left(ok)) => ok,
@@ -601,6 +673,13 @@ pub fn server_has_capability(
             Some(_) => true,
             None => false,
         },
+        CAPABILITY_WORKSPACE_DIAGNOSTIC => match &server_capabilities.diagnostic_provider {
+            Some(DiagnosticServerCapabilities::Options(options)) => options.workspace_diagnostics,
+            Some(DiagnosticServerCapabilities::RegistrationOptions(options)) => {
+                options.diagnostic_options.workspace_diagnostics
+            }
+            None => false,
+        },
         CAPABILITY_WORKSPACE_SYMBOL => match server_capabilities.workspace_symbol_provider {
             Some(OneOf::Left(ok)) => ok,
             Some(OneOf::Right(_)) => true,
@@ -663,6 +742,7 @@ pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
                 .or_default()
                 .push(server_name);
         }
+        probe_feature(to_editor, entry, &mut features, CAPABILITY_FOLDING_RANGE);
         probe_feature(to_editor, entry, &mut features, CAPABILITY_WORKSPACE_SYMBOL);
         probe_feature(to_editor, entry, &mut features, CAPABILITY_FORMATTING);
         probe_feature(to_editor, entry, &mut features, CAPABILITY_RANGE_FORMATTING);
@@ -675,12 +755,32 @@ pub fn capabilities(meta: EditorMeta, ctx: &mut Context) {
             CAPABILITY_CODE_ACTIONS_RESOLVE,
         );
         probe_feature(to_editor, entry, &mut features, CAPABILITY_CODE_LENS);
+        probe_feature(to_editor, entry, &mut features, CAPABILITY_DOCUMENT_LINK);
+        probe_feature(to_editor, entry, &mut features, CAPABILITY_DOCUMENT_COLOR);
         probe_feature(to_editor, entry, &mut features, CAPABILITY_CALL_HIERARCHY);
         features
             .entry("lsp-diagnostics".to_string())
             .or_default()
             .push(server_name);
+        probe_feature(
+            to_editor,
+            entry,
+            &mut features,
+            CAPABILITY_DOCUMENT_DIAGNOSTIC,
+        );
+        probe_feature(
+            to_editor,
+            entry,
+            &mut features,
+            CAPABILITY_WORKSPACE_DIAGNOSTIC,
+        );
         probe_feature(to_editor, entry, &mut features, CAPABILITY_INLAY_HINTS);
+        probe_feature(
+            to_editor,
+            entry,
+            &mut features,
+            CAPABILITY_LINKED_EDITING_RANGE,
+        );
 
         // NOTE controller should park request for capabilities until they are available thus it should
         // be safe to unwrap here (otherwise something unexpectedly wrong and it's better to panic)
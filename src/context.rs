@@ -1,4 +1,10 @@
+use crate::edit_journal::EditJournal;
+use crate::edit_preview::PendingWorkspaceEdit;
 use crate::editor_transport::{self, ToEditorSender};
+use crate::language_features::completion::CompletionState;
+use crate::language_features::inlay_hints::InlayHintLines;
+use crate::language_features::semantic_tokens::SemanticTokensState;
+use crate::language_features::signature_help::SignatureHelpState;
 use crate::language_server_transport::LanguageServerTransport;
 use crate::text_sync::CompiledFileSystemWatcher;
 use crate::thread_worker::Worker;
@@ -66,33 +72,41 @@ pub struct Context {
     pub batch_sizes: HashMap<BatchNumber, HashMap<ServerId, usize>>,
     pub batches:
         HashMap<BatchNumber, (Vec<(ServerId, serde_json::value::Value)>, ResponsesCallback)>,
+    // The roots that serve each buffer, so a root can be removed when its last buffer closes.
+    pub buffer_roots: HashMap<String, Vec<(ServerId, RootPath)>>,
     pub buffer_tombstones: HashSet<String>,
     pub server_tombstones: HashSet<String>,
     pub code_lenses: HashMap<String, Vec<(ServerId, CodeLens)>>,
-    pub completion_items: Vec<(ServerId, CompletionItem)>,
-    pub completion_items_timestamp: i32,
-    // We currently only track one client's completion items, to simplify cleanup (else we
-    // might need to hook into ClientClose). Track the client name, so we can check if the
-    // completions are valid.
-    pub completion_last_client: Option<ClientId>,
+    // Removed when Kakoune reports the client closing.
+    pub completions: HashMap<ClientId, CompletionState>,
     pub config: Config,
     pub diagnostics: HashMap<String, Vec<(ServerId, Diagnostic)>>,
+    // The result ID and items of the last pulled diagnostic report for each server and file,
+    // so unchanged reports can be resolved. Removed when the file is closed or the server exits.
+    pub diagnostic_reports: HashMap<(ServerId, String), (Option<String>, Vec<Diagnostic>)>,
     pub documents: HashMap<String, Document>,
     pub dynamic_config: DynamicConfig,
     pub inlay_hints: HashMap<String, Vec<(ServerId, InlayHint)>>,
+    pub inlay_hint_lines: HashMap<String, InlayHintLines>,
     pub language_servers: BTreeMap<ServerId, ServerSettings>,
     pub route_cache: HashMap<(ServerName, RootPath), ServerId>,
+    pub semantic_tokens: HashMap<String, SemanticTokensState>,
+    // Removed when Kakoune reports the client closing.
+    pub signature_help: HashMap<ClientId, SignatureHelpState>,
     pub outstanding_requests:
         HashMap<(ServerId, &'static str, String, Option<ClientId>), OutstandingRequests>,
     pub pending_requests: Vec<EditorRequest>,
     pub pending_requests_from_future: Vec<EditorRequest>,
     pub pending_message_requests: VecDeque<(Id, ServerId, ShowMessageRequestParams)>,
+    pub pending_workspace_edit: Option<PendingWorkspaceEdit>,
     pub request_counter: u64,
     pub response_waitlist: HashMap<Id, (EditorMeta, &'static str, BatchNumber, bool)>,
     pub session: SessionId,
     pub to_editor: ToEditorSender,
     pub work_done_progress: HashMap<NumberOrString, Option<WorkDoneProgressBegin>>,
     pub work_done_progress_report_timestamp: time::Instant,
+    // On-disk changes of the last workspace edit, for lsp-undo-workspace-edit.
+    pub workspace_edit_journal: Option<EditJournal>,
     pub pending_file_watchers:
         HashMap<(ServerId, String, Option<PathBuf>), Vec<CompiledFileSystemWatcher>>,
     pub file_watcher: Option<FileWatcher>,
@@ -109,29 +123,34 @@ impl Context {
             batch_count: 0,
             batch_sizes: Default::default(),
             batches: Default::default(),
+            buffer_roots: Default::default(),
             buffer_tombstones: Default::default(),
             server_tombstones: Default::default(),
             code_lenses: Default::default(),
-            completion_items: vec![],
-            completion_items_timestamp: i32::MAX,
-            completion_last_client: None,
+            completions: Default::default(),
             config,
             diagnostics: Default::default(),
+            diagnostic_reports: Default::default(),
             documents: Default::default(),
             dynamic_config: DynamicConfig::default(),
             inlay_hints: Default::default(),
+            inlay_hint_lines: Default::default(),
             language_servers: BTreeMap::new(),
             route_cache: HashMap::new(),
+            semantic_tokens: Default::default(),
+            signature_help: Default::default(),
             outstanding_requests: HashMap::default(),
             pending_requests: vec![],
             pending_requests_from_future: vec![],
             pending_message_requests: VecDeque::new(),
+            pending_workspace_edit: None,
             request_counter: 0,
             response_waitlist: HashMap::default(),
             session,
             to_editor: to_editor.clone(),
             work_done_progress: HashMap::default(),
             work_done_progress_report_timestamp: time::Instant::now(),
+            workspace_edit_journal: None,
             pending_file_watchers: HashMap::default(),
             file_watcher: None,
             legacy_filetypes,
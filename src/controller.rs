@@ -23,6 +23,7 @@ use crate::language_features::{selection_range, *};
 use crate::log::DEBUG;
 use crate::progress;
 use crate::project_root::find_project_root;
+use crate::show_document;
 use crate::show_message::{self, MessageRequestResponse};
 use crate::text_sync::*;
 use crate::thread_worker::Worker;
@@ -32,22 +33,30 @@ use crate::workspace::{
     self, EditorApplyEdit, EditorDidChangeConfigurationParams, EditorExecuteCommand,
 };
 use crate::{context::*, set_logger};
-use crate::{diagnostics, do_cleanup};
+use crate::{diagnostics, do_cleanup, edit_preview};
 use crate::{language_server_transport, LAST_CLIENT};
 use ccls::{EditorCallParams, EditorInheritanceParams, EditorMemberParams, EditorNavigateParams};
 use code_lens::{text_document_code_lens, CodeLensOptions};
 use crossbeam_channel::{after, never, tick, Receiver, Select, Sender};
+use document_color::EditorColorPresentationParams;
+use document_link::{DocumentLinkAction, EditorDocumentLinkParams};
+use edit_preview::WorkspaceEditToggleParams;
+use file_operations::FileOperation;
+use folding_range::FoldObjectParams;
 use indoc::formatdoc;
-use inlay_hints::{InlayHintApplyParams, InlayHintsOptions};
+use inlay_hints::{InlayHintApplyParams, InlayHintGotoParams, InlayHintsOptions};
 use itertools::Itertools;
 use jsonrpc_core::{Call, ErrorCode, MethodCall, Output, Params};
 use lean::EditorPlainGoalParams;
 use libc::O_NONBLOCK;
+use linked_editing_range::EditorLinkedEditingRangeParams;
 use lsp_types::error_codes::CONTENT_MODIFIED;
 use lsp_types::notification::Notification;
 use lsp_types::request::Request;
 use lsp_types::*;
+use semantic_tokens::EditorSemanticTokensParams;
 use serde::Deserialize;
+use signature_help::{EditorSignatureHelpParams, SignatureHelpCycleParams};
 use sloggers::types::Severity;
 
 #[derive(Default)]
@@ -421,7 +430,20 @@ fn dispatch_fifo_request(
         COMPLETE_EXECUTE_COMMAND => Box::new(CompletionParameters {
             response_fifo: state.next()?,
         }),
+        "kakoune/client-close" => Box::new(ClientId(state.next()?)),
         "kakoune/exit" => Box::new(()),
+        "kakoune/fold-object" => Box::new(FoldObjectParams {
+            count: state.next()?,
+            mode: state.next()?,
+            selections_desc: {
+                let selection_count = state.next()?;
+                state.next_vec(selection_count)?
+            },
+            fold_kinds: {
+                let num_fold_kinds = state.next()?;
+                state.next_vec(num_fold_kinds)?
+            },
+        }),
         "kakoune/goto-document-symbol" => Box::new(GotoSymbolParams {
             goto_symbol: state.next()?,
         }),
@@ -439,6 +461,17 @@ fn dispatch_fifo_request(
             },
             kind: inlay_hints::InlayHintApplyKind::Selected,
         }),
+        "kakoune/signature-help-cycle" => Box::new(SignatureHelpCycleParams {
+            step: state.next()?,
+        }),
+        "kakoune/inlay-hint-goto" => Box::new(InlayHintGotoParams {
+            selection_desc: state.next()?,
+        }),
+        "kakoune/inlay-hint-hover" => Box::new(EditorHoverParams {
+            selection_desc: state.next()?,
+            tabstop: state.next()?,
+            hover_client: None,
+        }),
         "kakoune/next-or-previous-symbol" => {
             let num_symbol_kinds = state.next()?;
             Box::new(NextOrPrevSymbolParams {
@@ -471,6 +504,12 @@ fn dispatch_fifo_request(
         "kakoune/textDocument/codeLens" => Box::new(CodeLensOptions {
             selection_desc: state.next()?,
         }),
+        "kakoune/undo-workspace-edit" => Box::new(()),
+        "kakoune/workspace-edit-accept" => Box::new(()),
+        "kakoune/workspace-edit-reject" => Box::new(()),
+        "kakoune/workspace-edit-toggle-file" => Box::new(WorkspaceEditToggleParams {
+            line: state.next()?,
+        }),
         "kakoune/did-change-option" => {
             let hook_param = state.next::<String>()?;
             let Some((key, value)) = hook_param.split_once('=') else {
@@ -524,6 +563,8 @@ fn dispatch_fifo_request(
             position: state.next()?,
             completion: EditorCompletion {
                 offset: state.next()?,
+                replace: state.next()?,
+                max_items: state.next()?,
             },
         }),
         "textDocument/definition" => {
@@ -551,12 +592,23 @@ fn dispatch_fifo_request(
             draft: state.text_of_buffer()?,
         }),
         "textDocument/didSave" => Box::new(()),
+        "textDocument/willSave" => Box::new(()),
+        "textDocument/willSaveWaitUntil" => {
+            let timeout: u32 = state.next()?;
+            Box::new(TextDocumentWillSaveWaitUntilParams {
+                response_fifo: TimedResponseFifo::new(
+                    ResponseFifo::new(state.next()?),
+                    Duration::from_millis(timeout.into()),
+                ),
+            })
+        }
         "textDocument/documentHighlight" => {
             word_regex = Some(state.next()?);
             Box::new(PositionParams {
                 position: state.next()?,
             })
         }
+        "textDocument/foldingRange" => Box::new(()),
         "textDocument/formatting" => {
             let params = Box::new(<FormattingOptions as Deserializable>::deserialize(state)?);
             let is_sync = state.next::<String>()? == "is-sync";
@@ -574,13 +626,32 @@ fn dispatch_fifo_request(
             tabstop: state.next()?,
             hover_client: state.next::<Option<String>>()?.map(ClientId),
         }),
-        "textDocument/inlayHint" => Box::new(InlayHintsOptions {
-            buf_line_count: state.next()?,
+        "textDocument/inlayHint" => {
+            let buf_line_count = state.next()?;
+            let window_line = state.next()?;
+            let _window_column: String = state.next()?;
+            let window_height = state.next()?;
+            let _window_width: String = state.next()?;
+            Box::new(InlayHintsOptions {
+                buf_line_count,
+                window_line,
+                window_height,
+            })
+        }
+        "textDocument/onTypeFormatting" => Box::new(OnTypeFormattingParams {
+            position: state.next()?,
+            ch: state.next()?,
+            formatting_options: state.next()?,
         }),
         "textDocument/prepareCallHierarchy" => Box::new(CallHierarchyParams {
             position: state.next()?,
             incoming_or_outgoing: state.next()?,
         }),
+        "textDocument/prepareTypeHierarchy" => Box::new(TypeHierarchyParams {
+            position: state.next()?,
+            supertypes_or_subtypes: state.next()?,
+            depth: state.next::<i32>()?.max(1) as u32,
+        }),
         "textDocument/rangeFormatting" => {
             let params = Box::new(RangeFormattingParams {
                 formatting_options: state.next()?,
@@ -602,6 +673,9 @@ fn dispatch_fifo_request(
                 position: state.next()?,
             })
         }
+        "textDocument/prepareRename" => Box::new(PositionParams {
+            position: state.next()?,
+        }),
         "textDocument/rename" => Box::new(TextDocumentRenameParams {
             position: state.next()?,
             new_name: state.next()?,
@@ -613,10 +687,56 @@ fn dispatch_fifo_request(
                 state.next_vec(selection_count)?
             },
         }),
-        "textDocument/signatureHelp" => Box::new(PositionParams {
+        "workspace/willCreateFiles" | "workspace/didCreateFiles" => {
+            Box::new(FileOperation::Create {
+                path: state.next()?,
+            })
+        }
+        "workspace/willRenameFiles" | "workspace/didRenameFiles" => {
+            Box::new(FileOperation::Rename {
+                old_path: state.next()?,
+                new_path: state.next()?,
+            })
+        }
+        "workspace/willDeleteFiles" | "workspace/didDeleteFiles" => {
+            Box::new(FileOperation::Delete {
+                path: state.next()?,
+            })
+        }
+        "textDocument/colorPresentation" => Box::new(EditorColorPresentationParams {
+            position: state.next()?,
+        }),
+        "textDocument/documentColor" => Box::new(()),
+        "textDocument/documentLink" => Box::new(EditorDocumentLinkParams {
+            action: match state.next::<String>()?.as_str() {
+                "menu" => DocumentLinkAction::Menu,
+                "open" => DocumentLinkAction::Open,
+                "highlight" => DocumentLinkAction::Highlight,
+                _ => panic!("invalid request"),
+            },
+            position: state.next()?,
+        }),
+        "textDocument/linkedEditingRange" => Box::new(EditorLinkedEditingRangeParams {
+            position: state.next()?,
+            selection_desc: state.next()?,
+        }),
+        "textDocument/signatureHelp" => Box::new(EditorSignatureHelpParams {
             position: state.next()?,
+            trigger_character: state.next()?,
+            is_retrigger: state.next()?,
         }),
-        "textDocument/semanticTokens/full" => Box::new(()),
+        "textDocument/semanticTokens/full" => {
+            let max_full_lines = state.next()?;
+            let window_line = state.next()?;
+            let _window_column: String = state.next()?;
+            let window_height = state.next()?;
+            let _window_width: String = state.next()?;
+            Box::new(EditorSemanticTokensParams {
+                max_full_lines,
+                window_line,
+                window_height,
+            })
+        }
         "textDocument/switchSourceHeader" => Box::new(()),
         "window/showMessageRequest/showNext" => Box::new(()),
         "window/showMessageRequest/respond" => Box::new(MessageRequestResponse {
@@ -628,6 +748,9 @@ fn dispatch_fifo_request(
         "window/workDoneProgress/cancel" => Box::new(WorkDoneProgressCancelParams {
             token: state.next()?,
         }),
+        "workspace/diagnostic" => Box::new(PositionParams {
+            position: state.next()?,
+        }),
         "workspace/didChangeConfiguration" =>
         {
             #[allow(deprecated)]
@@ -1152,7 +1275,9 @@ pub fn start(
                                     match failure.error.code {
                                         code if code
                                             == ErrorCode::ServerError(CONTENT_MODIFIED)
-                                            || method == request::CodeActionRequest::METHOD => {}
+                                            || method == request::CodeActionRequest::METHOD
+                                            || method
+                                                == request::DocumentDiagnosticRequest::METHOD => {}
                                         code => {
                                             let msg = match code {
                                                 ErrorCode::MethodNotFound => format!(
@@ -1209,6 +1334,33 @@ pub fn start(
 }
 
 pub fn process_editor_request(ctx: &mut Context, mut request: EditorRequest) -> ControlFlow<()> {
+    // These commands do not depend on the current buffer's language servers. The workspace
+    // edit preview is a scratch buffer.
+    match request.method.as_str() {
+        "kakoune/client-close" => {
+            let client: &ClientId = request.params.downcast_ref();
+            completion::client_close(client, ctx);
+            signature_help::client_close(client, ctx);
+            return ControlFlow::Continue(());
+        }
+        "kakoune/undo-workspace-edit" => {
+            workspace::undo_workspace_edit(request.meta, ctx);
+            return ControlFlow::Continue(());
+        }
+        "kakoune/workspace-edit-accept" => {
+            edit_preview::accept(request.meta, ctx);
+            return ControlFlow::Continue(());
+        }
+        "kakoune/workspace-edit-reject" => {
+            edit_preview::reject(request.meta, ctx);
+            return ControlFlow::Continue(());
+        }
+        "kakoune/workspace-edit-toggle-file" => {
+            edit_preview::toggle_file(request.meta, request.params.unbox(), ctx);
+            return ControlFlow::Continue(());
+        }
+        _ => (),
+    }
     if let Some(flow) = route_request(ctx, &mut request.meta, &request.method) {
         return flow;
     }
@@ -1244,6 +1396,8 @@ pub fn process_editor_request(ctx: &mut Context, mut request: EditorRequest) ->
                     | notification::DidChangeConfiguration::METHOD
                     | notification::DidCloseTextDocument::METHOD
                     | notification::DidSaveTextDocument::METHOD
+                    | notification::WillSaveTextDocument::METHOD
+                    | request::WillSaveWaitUntil::METHOD
                     | request::CodeLensRequest::METHOD
             )
         {
@@ -1479,23 +1633,21 @@ fn route_request(
     };
 
     let mut to_initialize = vec![];
+    let mut buffer_roots = vec![];
     'server: for (server_name, root) in server_addresses {
         if let Some(&server_id) = ctx.route_cache.get(&(server_name.clone(), root.clone())) {
             meta.servers.push(server_id);
+            buffer_roots.push((server_id, root));
             continue;
         }
-        for &server_id in ctx.language_servers.keys() {
+        let candidates: Vec<_> = ctx.language_servers.keys().copied().collect();
+        for server_id in candidates {
             if !can_serve(ctx, server_id, &server_name, &root) {
                 continue;
             }
-            ctx.language_servers
-                .get_mut(&server_id)
-                .unwrap()
-                .roots
-                .push(root.clone());
-            ctx.route_cache
-                .insert((server_name.clone(), root.clone()), server_id);
+            workspace::add_workspace_folder(server_id, &root, ctx);
             meta.servers.push(server_id);
+            buffer_roots.push((server_id, root));
             continue 'server;
         }
 
@@ -1561,9 +1713,12 @@ fn route_request(
             workaround_eslint: server_config.workaround_eslint.unwrap_or_default(),
         };
         ctx.language_servers.insert(server_id, server_settings);
-        ctx.route_cache.insert((server_name, root), server_id);
+        ctx.route_cache
+            .insert((server_name, root.clone()), server_id);
+        buffer_roots.push((server_id, root));
         to_initialize.push(server_id);
     }
+    ctx.buffer_roots.insert(meta.buffile.clone(), buffer_roots);
     if !to_initialize.is_empty() {
         initialize(meta.clone(), ctx, to_initialize);
     }
@@ -1642,6 +1797,7 @@ fn dispatch_incoming_editor_request(request: EditorRequest, ctx: &mut Context) -
         notification::DidChangeTextDocument::METHOD,
         notification::DidCloseTextDocument::METHOD,
         notification::DidSaveTextDocument::METHOD,
+        notification::WillSaveTextDocument::METHOD,
         notification::DidChangeConfiguration::METHOD,
         notification::Exit::METHOD,
         notification::WorkDoneProgressCancel::METHOD,
@@ -1731,12 +1887,21 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) -> Control
         notification::DidSaveTextDocument::METHOD => {
             text_document_did_save(meta, ctx);
         }
+        notification::WillSaveTextDocument::METHOD => {
+            text_document_will_save(meta, ctx);
+        }
+        request::WillSaveWaitUntil::METHOD => {
+            text_document_will_save_wait_until(meta, params.unbox(), ctx);
+        }
         notification::DidChangeConfiguration::METHOD => {
             workspace::did_change_configuration(meta, params.unbox(), ctx);
         }
         request::CallHierarchyPrepare::METHOD => {
             call_hierarchy::call_hierarchy_prepare(meta, params.unbox(), ctx);
         }
+        request::TypeHierarchyPrepare::METHOD => {
+            type_hierarchy::type_hierarchy_prepare(meta, params.unbox(), ctx);
+        }
         request::CodeLensRequest::METHOD => {
             text_document_code_lens(meta, ctx);
         }
@@ -1792,6 +1957,28 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) -> Control
         request::SelectionRangeRequest::METHOD => {
             selection_range::text_document_selection_range(meta, params.unbox(), ctx);
         }
+        request::WillCreateFiles::METHOD
+        | request::WillRenameFiles::METHOD
+        | request::WillDeleteFiles::METHOD => {
+            file_operations::will_file_operation(meta, params.unbox(), ctx);
+        }
+        notification::DidCreateFiles::METHOD
+        | notification::DidRenameFiles::METHOD
+        | notification::DidDeleteFiles::METHOD => {
+            file_operations::did_file_operation(meta, params.unbox(), ctx);
+        }
+        request::DocumentColor::METHOD => {
+            document_color::text_document_document_color(meta, ctx);
+        }
+        request::ColorPresentationRequest::METHOD => {
+            document_color::text_document_color_presentation(meta, params.unbox(), ctx);
+        }
+        request::DocumentLinkRequest::METHOD => {
+            document_link::text_document_document_link(meta, params.unbox(), ctx);
+        }
+        request::LinkedEditingRange::METHOD => {
+            linked_editing_range::text_document_linked_editing_range(meta, params.unbox(), ctx);
+        }
         request::SignatureHelpRequest::METHOD => {
             signature_help::text_document_signature_help(meta, params.unbox(), ctx);
         }
@@ -1827,18 +2014,33 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) -> Control
         "kakoune/inlay-hint-apply-selected" => {
             inlay_hints::inlay_hint_apply(meta, params.unbox(), ctx);
         }
+        "kakoune/signature-help-cycle" => {
+            signature_help::signature_help_cycle(meta, params.unbox(), ctx);
+        }
+        "kakoune/inlay-hint-goto" => {
+            inlay_hints::inlay_hint_goto(meta, params.unbox(), ctx);
+        }
+        "kakoune/inlay-hint-hover" => {
+            inlay_hints::inlay_hint_hover(meta, params.unbox(), ctx);
+        }
         "kakoune/next-or-previous-symbol" => {
             document_symbol::next_or_prev_symbol(meta, params.unbox(), ctx);
         }
         "kakoune/object" => {
             document_symbol::object(meta, params.unbox(), ctx);
         }
+        "kakoune/fold-object" => {
+            folding_range::fold_object(meta, params.unbox(), ctx);
+        }
         "kakoune/goto-document-symbol" => {
             document_symbol::document_symbol_menu(meta, params.unbox(), ctx);
         }
         "kakoune/textDocument/codeLens" => {
             code_lens::resolve_and_perform_code_lens(meta, params.unbox(), ctx);
         }
+        request::FoldingRangeRequest::METHOD => {
+            folding_range::folding_ranges(meta, ctx);
+        }
         request::Formatting::METHOD => {
             formatting::text_document_formatting(meta, response_fifo, params.unbox(), ctx);
         }
@@ -1853,12 +2055,21 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) -> Control
         request::WorkspaceSymbolRequest::METHOD => {
             workspace::workspace_symbol(meta, params.unbox(), ctx);
         }
+        request::OnTypeFormatting::METHOD => {
+            on_type_formatting::text_document_on_type_formatting(meta, params.unbox(), ctx);
+        }
+        request::PrepareRenameRequest::METHOD => {
+            rename::text_document_prepare_rename(meta, params.unbox(), ctx);
+        }
         request::Rename::METHOD => {
             rename::text_document_rename(meta, params.unbox(), ctx);
         }
         "textDocument/diagnostics" => {
             diagnostics::editor_diagnostics(meta, params.unbox(), ctx);
         }
+        request::WorkspaceDiagnosticRequest::METHOD => {
+            diagnostics::workspace_diagnostic(meta, params.unbox(), ctx);
+        }
         "capabilities" => {
             capabilities::capabilities(meta, ctx);
         }
@@ -1874,7 +2085,7 @@ fn dispatch_editor_request(request: EditorRequest, ctx: &mut Context) -> Control
             }
         }
         request::SemanticTokensFullRequest::METHOD => {
-            semantic_tokens::tokens_request(meta, ctx);
+            semantic_tokens::tokens_request(meta, params.unbox(), ctx);
         }
 
         request::InlayHintRequest::METHOD => {
@@ -1956,7 +2167,7 @@ fn dispatch_server_request(
     let method: &str = &request.method;
     let result = match method {
         request::ApplyWorkspaceEdit::METHOD => {
-            workspace::apply_edit_from_server(meta, server_id, request.params, ctx)
+            return workspace::apply_edit_from_server(meta, server_id, request, ctx);
         }
         request::RegisterCapability::METHOD => {
             let params: RegistrationParams = request
@@ -1973,9 +2184,33 @@ fn dispatch_server_request(
                         )
                     }
                     notification::DidChangeWorkspaceFolders::METHOD => {
-                        // Since we only support one root path, we are never going to send
-                        // "workspace/didChangeWorkspaceFolders" anyway, so let's not issue a warning.
-                        continue;
+                        let capabilities = ctx
+                            .language_servers
+                            .get_mut(&server_id)
+                            .unwrap()
+                            .capabilities
+                            .as_mut()
+                            .unwrap();
+                        let workspace = capabilities.workspace.get_or_insert_with(Default::default);
+                        let folders = workspace
+                            .workspace_folders
+                            .get_or_insert_with(Default::default);
+                        folders.change_notifications = Some(OneOf::Right(registration.id));
+                    }
+                    method @ (request::WillCreateFiles::METHOD
+                    | request::WillRenameFiles::METHOD
+                    | request::WillDeleteFiles::METHOD
+                    | notification::DidCreateFiles::METHOD
+                    | notification::DidRenameFiles::METHOD
+                    | notification::DidDeleteFiles::METHOD) => {
+                        let Some(options) = registration
+                            .register_options
+                            .and_then(|options| serde_json::from_value(options).ok())
+                        else {
+                            warn!(ctx.to_editor(), "{method} registration without filters");
+                            continue;
+                        };
+                        file_operations::register_file_operation(server_id, method, options, ctx);
                     }
                     "textDocument/semanticTokens" => {
                         let Some(options) = registration.register_options else {
@@ -2025,6 +2260,9 @@ fn dispatch_server_request(
         request::ShowMessageRequest::METHOD => {
             return show_message::show_message_request(meta, server_id, request, ctx);
         }
+        request::ShowDocument::METHOD => {
+            show_document::show_document(meta, server_id, request.params, ctx)
+        }
         request::CodeLensRefresh::METHOD => {
             ctx.exec(
                 meta,
@@ -2033,12 +2271,17 @@ fn dispatch_server_request(
             Ok(serde_json::Value::Null)
         }
         request::InlayHintRefreshRequest::METHOD => {
+            ctx.inlay_hint_lines.clear();
             ctx.exec(
                 meta,
                 "evaluate-commands -buffer * unset-option buffer lsp_inlay_hints_timestamp",
             );
             Ok(serde_json::Value::Null)
         }
+        request::WorkspaceDiagnosticRefresh::METHOD => {
+            diagnostics::workspace_diagnostic_refresh(server_id, ctx);
+            Ok(serde_json::Value::Null)
+        }
         request::SemanticTokensRefresh::METHOD => {
             ctx.exec(
                 meta,
@@ -2046,6 +2289,15 @@ fn dispatch_server_request(
             );
             Ok(serde_json::Value::Null)
         }
+        request::WorkspaceFoldersRequest::METHOD => {
+            let folders: Vec<_> = ctx
+                .server(server_id)
+                .roots
+                .iter()
+                .map(workspace::workspace_folder)
+                .collect();
+            Ok(serde_json::to_value(folders).unwrap())
+        }
         _ => {
             warn!(ctx.to_editor(), "Unsupported method: {}", method);
             Err(jsonrpc_core::Error::new(
@@ -2083,6 +2335,7 @@ fn dispatch_server_notification(
                 "language server {} exited",
                 &ctx.server(server_id).name
             );
+            ctx.diagnostic_reports.retain(|(id, _), _| *id != server_id);
         }
         notification::ShowMessage::METHOD => {
             let params: ShowMessageParams = params
@@ -1,3 +1,7 @@
+use crate::capabilities::{
+    attempt_server_capability, server_has_capability, CAPABILITY_DOCUMENT_DIAGNOSTIC,
+    CAPABILITY_WORKSPACE_DIAGNOSTIC,
+};
 use crate::context::*;
 use crate::markup::escape_kakoune_markup;
 use crate::position::*;
@@ -7,6 +11,7 @@ use crate::util::*;
 use itertools::EitherOrBoth;
 use itertools::Itertools;
 use jsonrpc_core::Params;
+use lsp_types::request::{DocumentDiagnosticRequest, WorkspaceDiagnosticRequest};
 use lsp_types::*;
 use std::collections::HashMap;
 use std::fmt::Write as _;
@@ -15,6 +20,230 @@ pub fn publish_diagnostics(server_id: ServerId, params: Params, ctx: &mut Contex
     let params: PublishDiagnosticsParams = params.parse().expect("Failed to parse params");
     let buffile = uri_to_file_path(&params.uri);
     let buffile = buffile.to_str().unwrap();
+    update_diagnostics(server_id, buffile, params.diagnostics, ctx);
+}
+
+/// Request diagnostics for the current buffer from servers that support the pull model.
+pub fn text_document_diagnostic(meta: EditorMeta, ctx: &mut Context) {
+    let eligible_servers: Vec<_> = ctx
+        .servers(&meta)
+        .filter(|(_, server)| {
+            server_has_capability(ctx.to_editor(), server, CAPABILITY_DOCUMENT_DIAGNOSTIC)
+        })
+        .collect();
+    if eligible_servers.is_empty() {
+        return;
+    }
+
+    let req_params = eligible_servers
+        .into_iter()
+        .map(|(server_id, server)| {
+            let previous_result_id = ctx
+                .diagnostic_reports
+                .get(&(server_id, meta.buffile.clone()))
+                .and_then(|(result_id, _)| result_id.clone());
+            (
+                server_id,
+                vec![DocumentDiagnosticParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: file_path_to_uri(&meta.buffile),
+                    },
+                    identifier: diagnostic_identifier(server),
+                    previous_result_id,
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                }],
+            )
+        })
+        .collect();
+    ctx.call::<DocumentDiagnosticRequest, _>(
+        meta,
+        RequestParams::Each(req_params),
+        |ctx: &mut Context, meta, results| {
+            // Drop reports for an outdated buffer, the pull for the newer version will follow.
+            if ctx
+                .documents
+                .get(&meta.buffile)
+                .is_some_and(|document| document.version != meta.version)
+            {
+                return;
+            }
+            for (server_id, result) in results {
+                let (report, related_documents) = match result {
+                    DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(
+                        report,
+                    )) => (
+                        Some(DocumentDiagnosticReportKind::Full(
+                            report.full_document_diagnostic_report,
+                        )),
+                        report.related_documents,
+                    ),
+                    DocumentDiagnosticReportResult::Report(
+                        DocumentDiagnosticReport::Unchanged(report),
+                    ) => (
+                        Some(DocumentDiagnosticReportKind::Unchanged(
+                            report.unchanged_document_diagnostic_report,
+                        )),
+                        report.related_documents,
+                    ),
+                    DocumentDiagnosticReportResult::Partial(partial) => {
+                        (None, partial.related_documents)
+                    }
+                };
+                if let Some(report) = report {
+                    apply_diagnostic_report(server_id, &meta.buffile, report, ctx);
+                }
+                for (uri, report) in related_documents.unwrap_or_default() {
+                    let buffile = uri_to_file_path(&uri);
+                    apply_diagnostic_report(server_id, buffile.to_str().unwrap(), report, ctx);
+                }
+            }
+        },
+    );
+}
+
+/// Pull diagnostics for the whole workspace, then show all known diagnostics.
+pub fn workspace_diagnostic(meta: EditorMeta, params: PositionParams, ctx: &mut Context) {
+    let eligible_servers: Vec<_> = ctx
+        .servers(&meta)
+        .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_WORKSPACE_DIAGNOSTIC))
+        .collect();
+    if eligible_servers.is_empty() {
+        editor_diagnostics(meta, params, ctx);
+        return;
+    }
+
+    let req_params = eligible_servers
+        .into_iter()
+        .map(|(server_id, server)| {
+            let previous_result_ids = ctx
+                .diagnostic_reports
+                .iter()
+                .filter(|((id, _), _)| *id == server_id)
+                .filter_map(|((_, buffile), (result_id, _))| {
+                    Some(PreviousResultId {
+                        uri: file_path_to_uri(buffile),
+                        value: result_id.clone()?,
+                    })
+                })
+                .collect();
+            (
+                server_id,
+                vec![WorkspaceDiagnosticParams {
+                    identifier: diagnostic_identifier(server),
+                    previous_result_ids,
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                }],
+            )
+        })
+        .collect();
+    ctx.call::<WorkspaceDiagnosticRequest, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx: &mut Context, meta, results| {
+            for (server_id, result) in results {
+                let items = match result {
+                    WorkspaceDiagnosticReportResult::Report(report) => report.items,
+                    WorkspaceDiagnosticReportResult::Partial(partial) => partial.items,
+                };
+                for item in items {
+                    let (uri, version, report) = match item {
+                        WorkspaceDocumentDiagnosticReport::Full(report) => (
+                            report.uri,
+                            report.version,
+                            DocumentDiagnosticReportKind::Full(
+                                report.full_document_diagnostic_report,
+                            ),
+                        ),
+                        WorkspaceDocumentDiagnosticReport::Unchanged(report) => (
+                            report.uri,
+                            report.version,
+                            DocumentDiagnosticReportKind::Unchanged(
+                                report.unchanged_document_diagnostic_report,
+                            ),
+                        ),
+                    };
+                    let buffile = uri_to_file_path(&uri);
+                    let buffile = buffile.to_str().unwrap();
+                    if let (Some(version), Some(document)) = (version, ctx.documents.get(buffile)) {
+                        if version != document.version as i64 {
+                            continue;
+                        }
+                    }
+                    apply_diagnostic_report(server_id, buffile, report, ctx);
+                }
+            }
+            editor_diagnostics(meta, params, ctx);
+        },
+    );
+}
+
+/// Re-pull diagnostics of all open buffers that the server has reported on before.
+pub fn workspace_diagnostic_refresh(server_id: ServerId, ctx: &mut Context) {
+    let buffiles: Vec<_> = ctx
+        .diagnostic_reports
+        .keys()
+        .filter(|(id, buffile)| *id == server_id && ctx.documents.contains_key(buffile))
+        .map(|(_, buffile)| buffile.clone())
+        .collect();
+    for buffile in buffiles {
+        let meta = EditorMeta {
+            version: ctx.documents[&buffile].version,
+            buffile,
+            servers: vec![server_id],
+            ..Default::default()
+        };
+        text_document_diagnostic(meta, ctx);
+    }
+}
+
+fn diagnostic_identifier(server: &ServerSettings) -> Option<String> {
+    match &server.capabilities.as_ref()?.diagnostic_provider {
+        Some(DiagnosticServerCapabilities::Options(options)) => options.identifier.clone(),
+        Some(DiagnosticServerCapabilities::RegistrationOptions(options)) => {
+            options.diagnostic_options.identifier.clone()
+        }
+        None => None,
+    }
+}
+
+fn apply_diagnostic_report(
+    server_id: ServerId,
+    buffile: &str,
+    report: DocumentDiagnosticReportKind,
+    ctx: &mut Context,
+) {
+    let key = (server_id, buffile.to_string());
+    let diagnostics = match report {
+        DocumentDiagnosticReportKind::Full(report) => {
+            ctx.diagnostic_reports
+                .insert(key, (report.result_id, report.items.clone()));
+            report.items
+        }
+        DocumentDiagnosticReportKind::Unchanged(report) => {
+            let Some((result_id, items)) = ctx.diagnostic_reports.get_mut(&key) else {
+                warn!(
+                    ctx.to_editor(),
+                    "unchanged diagnostic report for {} without a previous report", buffile
+                );
+                return;
+            };
+            *result_id = Some(report.result_id);
+            items.clone()
+        }
+    };
+    update_diagnostics(server_id, buffile, diagnostics, ctx);
+}
+
+/// Replace the diagnostics of the given server for a file, and render them if the file is open.
+fn update_diagnostics(
+    server_id: ServerId,
+    buffile: &str,
+    diagnostics: Vec<Diagnostic>,
+    ctx: &mut Context,
+) {
+    let new_diagnostics = diagnostics;
     let mut diagnostics: Vec<_> = ctx
         .diagnostics
         .remove(buffile)
@@ -22,12 +251,7 @@ pub fn publish_diagnostics(server_id: ServerId, params: Params, ctx: &mut Contex
         .into_iter()
         .filter(|(id, _)| id != &server_id)
         .collect();
-    let params: Vec<_> = params
-        .diagnostics
-        .into_iter()
-        .map(|d| (server_id, d))
-        .collect();
-    diagnostics.extend(params);
+    diagnostics.extend(new_diagnostics.into_iter().map(|d| (server_id, d)));
     ctx.diagnostics.insert(buffile.to_string(), diagnostics);
     let document = ctx.documents.get(buffile);
     if document.is_none() {
@@ -413,3 +637,75 @@ pub fn format_related_information(
                     .join("\n")
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor_transport::mock_to_editor;
+    use crate::text_sync::text_document_did_close;
+
+    fn diagnostic(message: &str) -> Diagnostic {
+        Diagnostic {
+            message: message.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn full(result_id: &str, items: Vec<Diagnostic>) -> DocumentDiagnosticReportKind {
+        DocumentDiagnosticReportKind::Full(FullDocumentDiagnosticReport {
+            result_id: Some(result_id.to_string()),
+            items,
+        })
+    }
+
+    fn unchanged(result_id: &str) -> DocumentDiagnosticReportKind {
+        DocumentDiagnosticReportKind::Unchanged(UnchangedDocumentDiagnosticReport {
+            result_id: result_id.to_string(),
+        })
+    }
+
+    #[test]
+    fn apply_diagnostic_report_resolves_unchanged_reports() {
+        let mut ctx = Context::new(
+            SessionId("session".to_string()),
+            mock_to_editor(),
+            Config::default(),
+        );
+        let server_id = ServerId::default();
+        let buffile = "/tmp/a.rs";
+        let messages = |ctx: &Context| {
+            ctx.diagnostics[buffile]
+                .iter()
+                .map(|(_, diagnostic)| diagnostic.message.clone())
+                .collect::<Vec<_>>()
+        };
+
+        // Without a previous report, there is nothing to resolve the unchanged one with.
+        apply_diagnostic_report(server_id, buffile, unchanged("1"), &mut ctx);
+        assert!(!ctx.diagnostics.contains_key(buffile));
+        assert!(ctx.diagnostic_reports.is_empty());
+
+        apply_diagnostic_report(
+            server_id,
+            buffile,
+            full("1", vec![diagnostic("a")]),
+            &mut ctx,
+        );
+        assert_eq!(messages(&ctx), ["a"]);
+        ctx.diagnostics.clear();
+        apply_diagnostic_report(server_id, buffile, unchanged("2"), &mut ctx);
+        assert_eq!(messages(&ctx), ["a"]);
+        assert_eq!(
+            ctx.diagnostic_reports[&(server_id, buffile.to_string())].0,
+            Some("2".to_string())
+        );
+
+        // Closing the file forgets its reports.
+        let meta = EditorMeta {
+            buffile: buffile.to_string(),
+            ..Default::default()
+        };
+        text_document_did_close(meta, &mut ctx);
+        assert!(ctx.diagnostic_reports.is_empty());
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use lsp_types::notification::*;
+use lsp_types::*;
+
+use crate::context::*;
+use crate::types::*;
+use crate::util::uri_to_file_path;
+
+/// Files, directories and symlinks at some path before it was deleted or overwritten.
+#[derive(Default)]
+struct Snapshot {
+    dirs: Vec<PathBuf>,
+    files: Vec<(PathBuf, Vec<u8>, fs::Permissions)>,
+    links: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Snapshot {
+    fn take(path: &Path) -> io::Result<Self> {
+        let mut snapshot = Snapshot::default();
+        snapshot.add(path)?;
+        Ok(snapshot)
+    }
+
+    fn add(&mut self, path: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        if metadata.is_symlink() {
+            self.links.push((path.to_owned(), fs::read_link(path)?));
+        } else if metadata.is_dir() {
+            self.dirs.push(path.to_owned());
+            for entry in fs::read_dir(path)? {
+                self.add(&entry?.path())?;
+            }
+        } else {
+            self.files
+                .push((path.to_owned(), fs::read(path)?, metadata.permissions()));
+        }
+        Ok(())
+    }
+
+    fn restore(&self) -> io::Result<()> {
+        // Directories are listed before their contents.
+        for dir in &self.dirs {
+            fs::create_dir_all(dir)?;
+        }
+        for (path, contents, permissions) in &self.files {
+            fs::write(path, contents)?;
+            fs::set_permissions(path, permissions.clone())?;
+        }
+        for (path, target) in &self.links {
+            symlink(target, path)?;
+        }
+        Ok(())
+    }
+}
+
+/// A change to the file system, with what is needed to revert it.
+enum JournalEntry {
+    Created(PathBuf),
+    Removed(Snapshot),
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    // A file that is not open in the editor, edited on disk and opened in the language server.
+    Edited {
+        server_id: ServerId,
+        uri: Uri,
+        // The version of the document that the language server was sent.
+        version: i32,
+        original: Vec<u8>,
+        written: Vec<u8>,
+        permissions: fs::Permissions,
+    },
+}
+
+/// Records the on-disk changes made while applying a workspace edit, so they can be rolled back
+/// if a later change fails, or undone with lsp-undo-workspace-edit.
+#[derive(Default)]
+pub struct EditJournal {
+    entries: Vec<JournalEntry>,
+    // Sent only once the whole edit succeeded.
+    did_open: Vec<(ServerId, DidOpenTextDocumentParams)>,
+}
+
+impl EditJournal {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Create the missing ancestors of a path.
+    pub fn create_parent_dirs(&mut self, path: &Path) -> io::Result<()> {
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+        let mut missing: Vec<_> = parent.ancestors().take_while(|dir| !dir.exists()).collect();
+        missing.reverse();
+        for dir in missing {
+            fs::create_dir(dir)?;
+            self.entries.push(JournalEntry::Created(dir.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Remember what was at this path before deleting or overwriting it.
+    pub fn save(&mut self, path: &Path) -> io::Result<()> {
+        if fs::symlink_metadata(path).is_ok() {
+            self.entries
+                .push(JournalEntry::Removed(Snapshot::take(path)?));
+        } else {
+            self.entries.push(JournalEntry::Created(path.to_owned()));
+        }
+        Ok(())
+    }
+
+    pub fn renamed(&mut self, from: PathBuf, to: PathBuf) {
+        self.entries.push(JournalEntry::Renamed { from, to });
+    }
+
+    pub fn edited(
+        &mut self,
+        server_id: ServerId,
+        original: Vec<u8>,
+        permissions: fs::Permissions,
+        params: DidOpenTextDocumentParams,
+    ) {
+        self.entries.push(JournalEntry::Edited {
+            server_id,
+            uri: params.text_document.uri.clone(),
+            version: params.text_document.version,
+            original,
+            written: params.text_document.text.clone().into_bytes(),
+            permissions,
+        });
+        self.did_open.push((server_id, params));
+    }
+
+    /// Notify language servers about the files that were edited on disk.
+    pub fn commit(&mut self, ctx: &mut Context) {
+        for (server_id, params) in self.did_open.drain(..) {
+            ctx.notify::<DidOpenTextDocument>(server_id, params);
+        }
+    }
+
+    /// Revert all changes of an edit that was not committed, in reverse order.
+    pub fn rollback(self) -> io::Result<()> {
+        let mut result = Ok(());
+        for entry in self.entries.into_iter().rev() {
+            if let Err(e) = revert(&entry) {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Revert a committed edit, unless the edited files have changed or were opened in the editor
+    /// since. Once opened, the editor owns the document that the language server sees.
+    pub fn undo(self, ctx: &mut Context) -> io::Result<()> {
+        for entry in &self.entries {
+            if let JournalEntry::Edited { uri, written, .. } = entry {
+                let path = uri_to_file_path(uri);
+                if path
+                    .to_str()
+                    .is_some_and(|buffile| ctx.documents.contains_key(buffile))
+                {
+                    return Err(io::Error::other(format!(
+                        "{} was opened since the workspace edit, undo the changes in the editor",
+                        path.display()
+                    )));
+                }
+                if fs::read(&path).ok().as_ref() != Some(written) {
+                    return Err(io::Error::other(format!(
+                        "{} has changed since the workspace edit",
+                        path.display()
+                    )));
+                }
+            }
+        }
+        let mut result = Ok(());
+        for entry in self.entries.into_iter().rev() {
+            if let Err(e) = revert(&entry) {
+                result = result.and(Err(e));
+                continue;
+            }
+            if let JournalEntry::Edited {
+                server_id,
+                uri,
+                version,
+                original,
+                ..
+            } = entry
+            {
+                ctx.notify::<DidChangeTextDocument>(
+                    server_id,
+                    DidChangeTextDocumentParams {
+                        text_document: VersionedTextDocumentIdentifier {
+                            uri,
+                            version: version + 1,
+                        },
+                        content_changes: vec![TextDocumentContentChangeEvent {
+                            range: None,
+                            range_length: None,
+                            text: String::from_utf8_lossy(&original).to_string(),
+                        }],
+                    },
+                );
+            }
+        }
+        result
+    }
+}
+
+fn revert(entry: &JournalEntry) -> io::Result<()> {
+    match entry {
+        JournalEntry::Created(path) => match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir(path),
+            Ok(_) => fs::remove_file(path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        },
+        JournalEntry::Removed(snapshot) => snapshot.restore(),
+        JournalEntry::Renamed { from, to } => fs::rename(to, from),
+        JournalEntry::Edited {
+            uri,
+            original,
+            permissions,
+            ..
+        } => {
+            let path = uri_to_file_path(uri);
+            fs::write(&path, original)?;
+            fs::set_permissions(&path, permissions.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_restores_renamed_deleted_and_created_files() {
+        let root = std::env::temp_dir().join(format!("kak-lsp-journal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("a"), "a").unwrap();
+        fs::write(root.join("dir/b"), "b").unwrap();
+
+        let mut journal = EditJournal::default();
+        let created = root.join("new/nested/c");
+        journal.create_parent_dirs(&created).unwrap();
+        journal.save(&created).unwrap();
+        fs::write(&created, "").unwrap();
+        journal.save(&root.join("dir")).unwrap();
+        fs::remove_dir_all(root.join("dir")).unwrap();
+        let renamed = root.join("renamed");
+        journal.save(&renamed).unwrap();
+        fs::rename(root.join("a"), &renamed).unwrap();
+        journal.renamed(root.join("a"), renamed.clone());
+
+        journal.rollback().unwrap();
+        assert_eq!(fs::read_to_string(root.join("a")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(root.join("dir/b")).unwrap(), "b");
+        assert!(!renamed.exists());
+        assert!(!root.join("new").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::{HashMap, HashSet};
+
+use jsonrpc_core::Id;
+use lsp_types::*;
+use ropey::Rope;
+
+use crate::context::*;
+use crate::text_edit::{apply_text_edits_to_rope, TextEditish};
+use crate::types::*;
+use crate::util::*;
+use crate::workspace;
+use crate::LAST_CLIENT;
+
+/// A workspace edit that is shown in the *workspace-edit* buffer until the user accepts or
+/// rejects it.
+pub struct PendingWorkspaceEdit {
+    server_id: ServerId,
+    meta: EditorMeta,
+    // Set if the server asked for the edit with workspace/applyEdit and is waiting for a reply.
+    request_id: Option<Id>,
+    edit: WorkspaceEdit,
+    // Versions of the open documents that the edit changes, when the preview was shown.
+    versions: HashMap<String, i32>,
+    rejected_files: HashSet<String>,
+    // An editor command to run once the edit was applied.
+    on_applied: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WorkspaceEditToggleParams {
+    pub line: usize,
+}
+
+/// One entry of the edit's document changes, with its index in documentChanges.
+enum Change {
+    Edit(Option<u32>, String, Vec<TextEdit>),
+    Op(u32, ResourceOp),
+}
+
+impl Change {
+    fn files(&self) -> Vec<String> {
+        let path = |uri: &Uri| uri_to_file_path(uri).to_str().unwrap().to_string();
+        match self {
+            Change::Edit(_, file, _) => vec![file.clone()],
+            Change::Op(_, ResourceOp::Create(op)) => vec![path(&op.uri)],
+            Change::Op(_, ResourceOp::Delete(op)) => vec![path(&op.uri)],
+            Change::Op(_, ResourceOp::Rename(op)) => vec![path(&op.old_uri), path(&op.new_uri)],
+        }
+    }
+}
+
+fn changes(edit: &WorkspaceEdit) -> Vec<Change> {
+    let path = |uri: &Uri| uri_to_file_path(uri).to_str().unwrap().to_string();
+    let text_edits = |edits: &[OneOf<TextEdit, AnnotatedTextEdit>]| {
+        edits.iter().map(|edit| edit.as_ref().clone()).collect()
+    };
+    match &edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => edits
+            .iter()
+            .enumerate()
+            .map(|(index, edit)| {
+                Change::Edit(
+                    Some(index as u32),
+                    path(&edit.text_document.uri),
+                    text_edits(&edit.edits),
+                )
+            })
+            .collect(),
+        Some(DocumentChanges::Operations(ops)) => ops
+            .iter()
+            .enumerate()
+            .map(|(index, op)| match op {
+                DocumentChangeOperation::Edit(edit) => Change::Edit(
+                    Some(index as u32),
+                    path(&edit.text_document.uri),
+                    text_edits(&edit.edits),
+                ),
+                DocumentChangeOperation::Op(op) => Change::Op(index as u32, op.clone()),
+            })
+            .collect(),
+        None => {
+            let mut changes: Vec<_> = edit
+                .changes
+                .iter()
+                .flatten()
+                .map(|(uri, edits)| Change::Edit(None, path(uri), edits.clone()))
+                .collect();
+            changes.sort_by_key(|change| change.files());
+            changes
+        }
+    }
+}
+
+/// Apply the edit, or show it in the *workspace-edit* buffer if previews are enabled.
+/// Returns None if the response is deferred until the user accepts or rejects the edit.
+/// The on_applied command is run once the edit was applied, and not at all if it was rejected.
+pub fn apply_or_preview_edit(
+    server_id: ServerId,
+    meta: EditorMeta,
+    response_fifo: Option<ResponseFifo>,
+    request_id: Option<Id>,
+    edit: WorkspaceEdit,
+    on_applied: Option<String>,
+    ctx: &mut Context,
+) -> Option<ApplyWorkspaceEditResponse> {
+    if !ctx.config.workspace_edit_preview || changes(&edit).is_empty() {
+        let response = workspace::apply_edit(server_id, meta.clone(), response_fifo, edit, ctx);
+        if let (true, Some(command)) = (response.applied, on_applied) {
+            ctx.exec(meta, command);
+        }
+        return Some(response);
+    }
+    if let Some(previous) = ctx.pending_workspace_edit.take() {
+        reply(
+            ctx,
+            previous,
+            ApplyWorkspaceEditResponse {
+                applied: false,
+                failure_reason: Some("superseded by another workspace edit".to_string()),
+                failed_change: None,
+            },
+        );
+    }
+    let versions = changes(&edit)
+        .iter()
+        .flat_map(|change| change.files())
+        .filter_map(|file| {
+            let version = ctx.documents.get(&file)?.version;
+            Some((file, version))
+        })
+        .collect();
+    let pending = PendingWorkspaceEdit {
+        server_id,
+        meta,
+        request_id,
+        edit,
+        versions,
+        rejected_files: HashSet::new(),
+        on_applied,
+    };
+    show_preview(&pending, response_fifo, 1, ctx);
+    ctx.pending_workspace_edit = Some(pending);
+    None
+}
+
+/// Apply the changes that were not deselected. If any were deselected, the server is told that
+/// the edit was not applied, pointing at the first change that was left out.
+pub fn accept(meta: EditorMeta, ctx: &mut Context) {
+    let Some(pending) = ctx.pending_workspace_edit.take() else {
+        ctx.show_error(meta, "no pending workspace edit");
+        return;
+    };
+    let modified = pending.versions.iter().find(|(buffile, version)| {
+        ctx.documents
+            .get(*buffile)
+            .is_some_and(|document| document.version != **version)
+    });
+    if let Some((buffile, _)) = modified {
+        let msg = format!(
+            "refusing to apply workspace edit: {} was modified after the preview was shown",
+            short_file_path(buffile, preview_root(&pending, ctx)),
+        );
+        ctx.show_error(meta, &msg);
+        reply(
+            ctx,
+            pending,
+            ApplyWorkspaceEditResponse {
+                applied: false,
+                failure_reason: Some(msg),
+                failed_change: None,
+            },
+        );
+        return;
+    }
+
+    let rejected: Vec<_> = changes(&pending.edit)
+        .into_iter()
+        .filter(|change| {
+            change
+                .files()
+                .iter()
+                .any(|file| pending.rejected_files.contains(file))
+        })
+        .collect();
+    let (edit, kept) = without_rejected_files(pending.edit.clone(), &pending.rejected_files);
+    let mut response =
+        workspace::apply_edit(pending.server_id, pending.meta.clone(), None, edit, ctx);
+    // Report indices into the edit that the server sent.
+    response.failed_change = response
+        .failed_change
+        .and_then(|index| kept.get(index as usize).copied());
+    if let (true, Some(command)) = (response.applied, pending.on_applied.clone()) {
+        ctx.exec(pending.meta.clone(), command);
+    }
+    if response.applied && !rejected.is_empty() {
+        let root = preview_root(&pending, ctx);
+        let mut files: Vec<_> = pending
+            .rejected_files
+            .iter()
+            .map(|file| short_file_path(file, root))
+            .collect();
+        files.sort();
+        response = ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some(format!("rejected by user: {}", files.join(", "))),
+            failed_change: rejected.iter().find_map(|change| match change {
+                Change::Edit(index, _, _) => *index,
+                Change::Op(index, _) => Some(*index),
+            }),
+        };
+    }
+    reply(ctx, pending, response);
+}
+
+pub fn reject(meta: EditorMeta, ctx: &mut Context) {
+    let Some(pending) = ctx.pending_workspace_edit.take() else {
+        ctx.show_error(meta, "no pending workspace edit");
+        return;
+    };
+    reply(
+        ctx,
+        pending,
+        ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some("rejected by user".to_string()),
+            failed_change: None,
+        },
+    );
+}
+
+/// Include or exclude the files of the change under the cursor.
+pub fn toggle_file(meta: EditorMeta, params: WorkspaceEditToggleParams, ctx: &mut Context) {
+    let Some(pending) = ctx.pending_workspace_edit.as_ref() else {
+        ctx.show_error(meta, "no pending workspace edit");
+        return;
+    };
+    let (_, sections) = render(pending, ctx);
+    let Some((_, files)) = sections
+        .into_iter()
+        .take_while(|(line, _)| *line <= params.line)
+        .last()
+    else {
+        ctx.show_error(meta, "no file under the cursor");
+        return;
+    };
+    let pending = ctx.pending_workspace_edit.as_mut().unwrap();
+    if files
+        .iter()
+        .any(|file| pending.rejected_files.contains(file))
+    {
+        for file in &files {
+            pending.rejected_files.remove(file);
+        }
+    } else {
+        pending.rejected_files.extend(files);
+    }
+    let pending = ctx.pending_workspace_edit.as_ref().unwrap();
+    show_preview(pending, None, params.line, ctx);
+}
+
+fn reply(ctx: &mut Context, pending: PendingWorkspaceEdit, response: ApplyWorkspaceEditResponse) {
+    if let Some(request_id) = pending.request_id {
+        ctx.reply(
+            pending.server_id,
+            request_id,
+            Ok(serde_json::to_value(response).unwrap()),
+        );
+    }
+}
+
+fn preview_root<'a>(pending: &PendingWorkspaceEdit, ctx: &'a Context) -> &'a str {
+    ctx.server(pending.server_id)
+        .roots
+        .first()
+        .map(|root| root.as_str())
+        .unwrap_or_default()
+}
+
+fn show_preview(
+    pending: &PendingWorkspaceEdit,
+    response_fifo: Option<ResponseFifo>,
+    line: usize,
+    ctx: &Context,
+) {
+    let (contents, _) = render(pending, ctx);
+    let command = format!(
+        "lsp-show-workspace-edit {} {}",
+        editor_quote(&contents),
+        line
+    );
+    // Edits requested by the server have no client, so show them in the last active one.
+    let command = if pending.meta.client.is_none() && response_fifo.is_none() {
+        format!(
+            "evaluate-commands -try-client '{}' {}",
+            LAST_CLIENT
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|client| client.as_str())
+                .unwrap_or_default(),
+            editor_quote(&command)
+        )
+    } else {
+        command
+    };
+    ctx.exec_fifo(pending.meta.clone(), response_fifo, command);
+}
+
+/// Render the edit as a unified diff. Also returns the first line of each change, along with
+/// the files it touches.
+fn render(pending: &PendingWorkspaceEdit, ctx: &Context) -> (String, Vec<(usize, Vec<String>)>) {
+    let server = ctx.server(pending.server_id);
+    let root = preview_root(pending, ctx);
+    let mut contents = format!(
+        "# Workspace edit from {}\n\
+         # lsp-workspace-edit-accept applies the selected changes, lsp-workspace-edit-reject discards the edit.\n\
+         # lsp-workspace-edit-toggle-file selects or deselects the file under the cursor.\n",
+        server.name
+    );
+    let mut sections = vec![];
+    // Contents of the files as they would be after the preceding changes, None if deleted.
+    let mut files: HashMap<String, Option<Rope>> = HashMap::new();
+    let current_text = |files: &mut HashMap<String, Option<Rope>>, file: &str| {
+        files
+            .entry(file.to_string())
+            .or_insert_with(|| {
+                ctx.documents
+                    .get(file)
+                    .map(|document| document.text.clone())
+                    .or_else(|| read_document(file).ok().map(|text| Rope::from_str(&text)))
+            })
+            .clone()
+    };
+    for change in changes(&pending.edit) {
+        let change_files = change.files();
+        let checkbox = if change_files
+            .iter()
+            .any(|file| pending.rejected_files.contains(file))
+        {
+            "[ ]"
+        } else {
+            "[x]"
+        };
+        contents.push('\n');
+        sections.push((contents.lines().count() + 1, change_files));
+        match change {
+            Change::Edit(_, file, mut edits) => {
+                let filename = short_file_path(&file, root);
+                contents.push_str(&format!("{} {}\n", checkbox, filename));
+                let old = current_text(&mut files, &file).unwrap_or_default();
+                edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+                match apply_text_edits_to_rope(old.clone(), edits, server.offset_encoding) {
+                    Ok(new) => {
+                        let new = Rope::from_str(&String::from_utf8_lossy(&new));
+                        contents.push_str(&format!("--- {}\n+++ {}\n", filename, filename));
+                        contents.push_str(&unified_diff(&old, &new, 3));
+                        files.insert(file, Some(new));
+                    }
+                    Err(e) => contents.push_str(&format!("# cannot apply edits: {}\n", e)),
+                }
+            }
+            Change::Op(_, ResourceOp::Create(op)) => {
+                let file = uri_to_file_path(&op.uri).to_str().unwrap().to_string();
+                contents.push_str(&format!(
+                    "{} create {}\n",
+                    checkbox,
+                    short_file_path(&file, root)
+                ));
+                if current_text(&mut files, &file).is_none() {
+                    files.insert(file, Some(Rope::new()));
+                }
+            }
+            Change::Op(_, ResourceOp::Delete(op)) => {
+                let file = uri_to_file_path(&op.uri).to_str().unwrap().to_string();
+                contents.push_str(&format!(
+                    "{} delete {}\n",
+                    checkbox,
+                    short_file_path(&file, root)
+                ));
+                files.insert(file, None);
+            }
+            Change::Op(_, ResourceOp::Rename(op)) => {
+                let from = uri_to_file_path(&op.old_uri).to_str().unwrap().to_string();
+                let to = uri_to_file_path(&op.new_uri).to_str().unwrap().to_string();
+                contents.push_str(&format!(
+                    "{} rename {} -> {}\n",
+                    checkbox,
+                    short_file_path(&from, root),
+                    short_file_path(&to, root)
+                ));
+                let text = current_text(&mut files, &from);
+                files.insert(from, None);
+                files.insert(to, text);
+            }
+        }
+    }
+    (contents, sections)
+}
+
+/// Drop the changes touching rejected files. Also returns the original index of each change
+/// that is kept.
+fn without_rejected_files(
+    mut edit: WorkspaceEdit,
+    rejected_files: &HashSet<String>,
+) -> (WorkspaceEdit, Vec<u32>) {
+    let kept: Vec<_> = changes(&edit)
+        .iter()
+        .filter(|change| {
+            !change
+                .files()
+                .iter()
+                .any(|file| rejected_files.contains(file))
+        })
+        .filter_map(|change| match change {
+            Change::Edit(index, _, _) => *index,
+            Change::Op(index, _) => Some(*index),
+        })
+        .collect();
+    let is_kept = |index: usize| kept.contains(&(index as u32));
+    match edit.document_changes.take() {
+        Some(DocumentChanges::Edits(edits)) => {
+            edit.document_changes = Some(DocumentChanges::Edits(
+                edits
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(index, edit)| is_kept(index).then_some(edit))
+                    .collect(),
+            ));
+        }
+        Some(DocumentChanges::Operations(ops)) => {
+            edit.document_changes = Some(DocumentChanges::Operations(
+                ops.into_iter()
+                    .enumerate()
+                    .filter_map(|(index, op)| is_kept(index).then_some(op))
+                    .collect(),
+            ));
+        }
+        None => {
+            if let Some(changes) = edit.changes.as_mut() {
+                changes.retain(|uri, _| {
+                    !rejected_files.contains(uri_to_file_path(uri).to_str().unwrap())
+                });
+            }
+        }
+    }
+    (edit, kept)
+}
+
+/// Line-based unified diff with the given number of context lines.
+fn unified_diff(old: &Rope, new: &Rope, context: usize) -> String {
+    // Ignore the empty line after a trailing newline.
+    let lines = |text: &Rope| {
+        let mut lines: Vec<_> = text.lines().map(|line| line.to_string()).collect();
+        if lines.last().is_some_and(|line| line.is_empty()) {
+            lines.pop();
+        }
+        lines
+    };
+    let old_lines = lines(old);
+    let new_lines = lines(new);
+
+    // Each line of the diff, with the old and new line numbers before it.
+    struct Script(Vec<(char, usize, usize)>);
+    impl diffs::Diff for Script {
+        type Error = ();
+        fn equal(&mut self, old: usize, new: usize, len: usize) -> Result<(), ()> {
+            self.0.extend((0..len).map(|i| (' ', old + i, new + i)));
+            Ok(())
+        }
+        fn delete(&mut self, old: usize, len: usize, new: usize) -> Result<(), ()> {
+            self.0.extend((0..len).map(|i| ('-', old + i, new)));
+            Ok(())
+        }
+        fn insert(&mut self, old: usize, new: usize, new_len: usize) -> Result<(), ()> {
+            self.0.extend((0..new_len).map(|i| ('+', old, new + i)));
+            Ok(())
+        }
+        fn replace(
+            &mut self,
+            old: usize,
+            old_len: usize,
+            new: usize,
+            new_len: usize,
+        ) -> Result<(), ()> {
+            self.delete(old, old_len, new)?;
+            self.insert(old + old_len, new, new_len)
+        }
+    }
+    let mut script = Script(vec![]);
+    let _result = diffs::patience::diff(
+        &mut script,
+        &old_lines,
+        0,
+        old_lines.len(),
+        &new_lines,
+        0,
+        new_lines.len(),
+    );
+    let script = script.0;
+
+    let changed: Vec<_> = (0..script.len()).filter(|&i| script[i].0 != ' ').collect();
+    let mut output = String::new();
+    let mut i = 0;
+    while i < changed.len() {
+        let mut j = i;
+        while j + 1 < changed.len() && changed[j + 1] - changed[j] <= 2 * context + 1 {
+            j += 1;
+        }
+        let start = changed[i].saturating_sub(context);
+        let end = (changed[j] + context + 1).min(script.len());
+        let hunk = &script[start..end];
+        let old_len = hunk.iter().filter(|(tag, _, _)| *tag != '+').count();
+        let new_len = hunk.iter().filter(|(tag, _, _)| *tag != '-').count();
+        let (_, old_start, new_start) = hunk[0];
+        output.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            old_start + usize::from(old_len != 0),
+            old_len,
+            new_start + usize::from(new_len != 0),
+            new_len,
+        ));
+        for &(tag, old_line, new_line) in hunk {
+            let line = if tag == '+' {
+                &new_lines[new_line]
+            } else {
+                &old_lines[old_line]
+            };
+            output.push(tag);
+            output.push_str(line.strip_suffix('\n').unwrap_or(line));
+            output.push('\n');
+        }
+        i = j + 1;
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    #[test]
+    fn unified_diff_merges_nearby_hunks() {
+        let old = Rope::from_str("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n16\n");
+        let new = Rope::from_str("1\ntwo\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n16\n17\n");
+        assert_eq!(
+            unified_diff(&old, &new, 3),
+            indoc! {"
+                @@ -1,5 +1,5 @@
+                 1
+                -2
+                +two
+                 3
+                 4
+                 5
+                @@ -14,3 +14,4 @@
+                 14
+                 15
+                 16
+                +17
+            "}
+        );
+        let old = Rope::from_str("1\n2\n3\n4\n5\n6\n7\n8\n");
+        let new = Rope::from_str("1\ntwo\n3\n4\nfive\n6\n7\n8\n");
+        assert_eq!(
+            unified_diff(&old, &new, 1),
+            indoc! {"
+                @@ -1,6 +1,6 @@
+                 1
+                -2
+                +two
+                 3
+                 4
+                -5
+                +five
+                 6
+            "}
+        );
+    }
+}
@@ -2,21 +2,66 @@ use crate::capabilities::attempt_server_capability;
 use crate::capabilities::CAPABILITY_COMPLETION;
 use crate::context::*;
 use crate::editor_transport::ToEditorSender;
+use crate::language_features::completion_ranking;
 use crate::markup::*;
 use crate::position::*;
+use crate::snippet;
 use crate::text_edit::apply_text_edits;
 use crate::types::*;
 use crate::util::*;
 use indoc::formatdoc;
 use itertools::Itertools;
-use lazy_static::lazy_static;
 use lsp_types::request::*;
 use lsp_types::*;
-use regex::Regex;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use unicode_width::UnicodeWidthStr;
 
+/// The completion items last shown in a Kakoune client.
+#[derive(Default)]
+pub struct CompletionState {
+    pub items: Vec<(ServerId, CompletionItem)>,
+    pub items_timestamp: i32,
+    // The buffer, line and column where the word being completed starts. While the user keeps
+    // typing this word, complete lists are filtered locally instead of being requested again.
+    pub start: Option<(String, u32, u32)>,
+    // Servers whose last completion list was incomplete, to be requested again on further input.
+    pub incomplete: HashSet<ServerId>,
+}
+
+/// A completion request whose response keeps the list's item defaults, which lsp_types drops.
+enum CompletionRequest {}
+
+impl Request for CompletionRequest {
+    type Params = CompletionParams;
+    type Result = Option<CompletionResult>;
+    const METHOD: &'static str = Completion::METHOD;
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(untagged)]
+enum CompletionResult {
+    Array(Vec<CompletionItem>),
+    List(CompletionListWithDefaults),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CompletionListWithDefaults {
+    is_incomplete: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    item_defaults: Option<CompletionItemDefaults>,
+    items: Vec<CompletionItem>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CompletionItemDefaults {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    commit_characters: Option<Vec<String>>,
+}
+
 pub fn text_document_completion(
     meta: EditorMeta,
     params: TextDocumentCompletionParams,
@@ -27,10 +72,34 @@ pub fn text_document_completion(
         .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_COMPLETION))
         .collect();
 
-    let req_params = eligible_servers
+    let (_, previous_char) = word_before_cursor(&meta, &params, ctx);
+    let start = (
+        meta.buffile.clone(),
+        params.position.line,
+        params.completion.offset,
+    );
+    let state = meta
+        .client
+        .as_ref()
+        .and_then(|client| ctx.completions.get(client));
+
+    let mut reused = HashSet::new();
+    let req_params: HashMap<_, _> = eligible_servers
         .into_iter()
-        .map(|(server_id, server_settings)| {
-            (
+        .filter_map(|(server_id, server_settings)| {
+            let trigger_characters = server_settings
+                .capabilities
+                .as_ref()
+                .and_then(|caps| caps.completion_provider.as_ref())
+                .and_then(|provider| provider.trigger_characters.as_deref())
+                .unwrap_or_default();
+            let Some(context) =
+                completion_context(state, &start, server_id, trigger_characters, previous_char)
+            else {
+                reused.insert(server_id);
+                return None;
+            };
+            Some((
                 server_id,
                 vec![CompletionParams {
                     text_document_position: TextDocumentPositionParams {
@@ -45,73 +114,172 @@ pub fn text_document_completion(
                         )
                         .unwrap(),
                     },
-                    context: None,
+                    context: Some(context),
                     work_done_progress_params: Default::default(),
                     partial_result_params: Default::default(),
                 }],
-            )
+            ))
         })
         .collect();
-    ctx.call::<Completion, _>(
+    if req_params.is_empty() {
+        editor_completion(meta, params, vec![], reused, ctx);
+        return;
+    }
+    ctx.call::<CompletionRequest, _>(
         meta,
         RequestParams::Each(req_params),
-        |ctx: &mut Context, meta, results| editor_completion(meta, params, results, ctx),
+        move |ctx: &mut Context, meta, results| {
+            editor_completion(meta, params, results, reused, ctx)
+        },
     );
 }
 
-fn sort_text(item: &CompletionItem) -> &str {
-    item.sort_text.as_ref().unwrap_or(&item.label)
+/// How to ask a server for completions, or None to filter its previous list locally. While the
+/// user keeps typing the word that starts at `start`, only incomplete lists are requested again.
+/// Otherwise, a trigger character is reported if the server declares the character before the
+/// cursor as one.
+fn completion_context(
+    state: Option<&CompletionState>,
+    start: &(String, u32, u32),
+    server_id: ServerId,
+    trigger_characters: &[String],
+    previous_char: Option<char>,
+) -> Option<CompletionContext> {
+    if let Some(state) = state.filter(|state| state.start.as_ref() == Some(start)) {
+        if !state.incomplete.contains(&server_id) {
+            return None;
+        }
+        return Some(CompletionContext {
+            trigger_kind: CompletionTriggerKind::TRIGGER_FOR_INCOMPLETE_COMPLETIONS,
+            trigger_character: None,
+        });
+    }
+    let trigger_character = previous_char
+        .map(String::from)
+        .filter(|c| trigger_characters.contains(c));
+    Some(CompletionContext {
+        trigger_kind: if trigger_character.is_some() {
+            CompletionTriggerKind::TRIGGER_CHARACTER
+        } else {
+            CompletionTriggerKind::INVOKED
+        },
+        trigger_character,
+    })
+}
+
+/// The text between the start of the completed word and the cursor, and the character before
+/// the cursor.
+fn word_before_cursor(
+    meta: &EditorMeta,
+    params: &TextDocumentCompletionParams,
+    ctx: &Context,
+) -> (String, Option<char>) {
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return (String::new(), None);
+    };
+    let line = get_line(params.position.line as usize - 1, &document.text);
+    let char_index =
+        |column: u32| line.byte_to_char((column as usize).saturating_sub(1).min(line.len_bytes()));
+    let cursor = char_index(params.position.column);
+    let start = char_index(params.completion.offset).min(cursor);
+    let previous_char = cursor.checked_sub(1).map(|index| line.char(index));
+    (line.slice(start..cursor).to_string(), previous_char)
 }
 
 fn editor_completion(
     meta: EditorMeta,
     params: TextDocumentCompletionParams,
-    results: Vec<(ServerId, Option<CompletionResponse>)>,
+    results: Vec<(ServerId, Option<CompletionResult>)>,
+    reused: HashSet<ServerId>,
     ctx: &mut Context,
 ) {
-    let mut items: Vec<(ServerId, CompletionItem)> = results
-        .into_iter()
-        .flat_map(|(server_id, items)| {
-            let items = match items {
-                Some(CompletionResponse::Array(items)) => items,
-                Some(CompletionResponse::List(list)) => list.items,
-                None => vec![],
-            };
+    let Some(client) = meta.client.clone() else {
+        return;
+    };
+    let (typed, _) = word_before_cursor(&meta, &params, ctx);
+    let version = meta.version;
 
-            items.into_iter().map(move |v| (server_id, v))
+    // Keep the lists of servers that were not asked again, and replace the others.
+    let state = ctx.completions.entry(client.clone()).or_default();
+    state
+        .items
+        .retain(|(server_id, _)| reused.contains(server_id));
+    state.incomplete.clear();
+    for (server_id, response) in results {
+        let new_items = match response {
+            Some(CompletionResult::Array(items)) => items,
+            Some(CompletionResult::List(list)) => {
+                if list.is_incomplete {
+                    state.incomplete.insert(server_id);
+                }
+                // The item's own commit characters take precedence over the list's defaults.
+                let commit_characters = list
+                    .item_defaults
+                    .and_then(|defaults| defaults.commit_characters);
+                let mut items = list.items;
+                for item in &mut items {
+                    if item.commit_characters.is_none() {
+                        item.commit_characters = commit_characters.clone();
+                    }
+                }
+                items
+            }
+            None => vec![],
+        };
+        state
+            .items
+            .extend(new_items.into_iter().map(|item| (server_id, item)));
+    }
+    state.start = Some((
+        meta.buffile.clone(),
+        params.position.line,
+        params.completion.offset,
+    ));
+    state.items_timestamp = version;
+
+    // Complete lists that were not requested again are filtered by the typed text.
+    let state = &ctx.completions[&client];
+    let priorities: HashMap<_, _> = state
+        .items
+        .iter()
+        .map(|(server_id, _)| *server_id)
+        .unique()
+        .map(|server_id| {
+            let priority = ctx
+                .server_config(&meta, &ctx.server(server_id).name)
+                .and_then(|cfg| cfg.completion_priority)
+                .unwrap_or_default();
+            (server_id, priority)
         })
         .collect();
-
-    // TODO Group by server?
-    items.sort_by(|(_left_server, left), (_right_server, right)| {
-        sort_text(left).cmp(sort_text(right))
-    });
-
-    let version = meta.version;
-    ctx.completion_items = items;
-    ctx.completion_items_timestamp = version;
-    let items = &ctx.completion_items;
-    if ctx.completion_last_client != meta.client {
-        ctx.completion_last_client.clone_from(&meta.client);
-    }
+    let items: Vec<_> = completion_ranking::rank(
+        &state.items,
+        &typed,
+        &reused,
+        &priorities,
+        params.completion.max_items,
+    )
+    .into_iter()
+    .map(|index| (index, &state.items[index]))
+    .collect();
 
     if items.is_empty() {
         return;
     }
 
-    // Maximum display width of any completion label.
+    // Maximum display width of any completion label, including its label detail.
     let maxwidth = items
         .iter()
-        .map(|(_, x)| UnicodeWidthStr::width(x.label.as_str()))
+        .map(|(_, (_, x))| label_width(x))
         .max()
         .unwrap_or(0);
 
     let mut inferred_offset: Option<u32> = None;
     let mut can_infer_offset = true;
+    let variables = snippet::variables(&meta, ctx);
 
     let items = items
-        .iter()
-        .enumerate()
+        .into_iter()
         .map(|(completion_item_index, (server_id, x))| {
             let server = ctx.server(*server_id);
             let maybe_resolve = if server
@@ -131,21 +299,43 @@ fn editor_completion(
             } else {
                 ""
             };
+            // With the replace range, the rest of the word after the cursor is deleted on accept.
+            let delete_suffix = match &x.text_edit {
+                Some(CompletionTextEdit::InsertAndReplace(edit)) if params.completion.replace => {
+                    ctx.documents.get(&meta.buffile).map_or(0, |document| {
+                        replaced_suffix_length(edit, &document.text, server.offset_encoding)
+                    })
+                }
+                _ => 0,
+            };
+            let delete_suffix = match delete_suffix {
+                0 => "".to_string(),
+                1 => "lsp-completion-on-accept %{ lsp-completion-delete-suffix '<a-:>;ld' }\n"
+                    .to_string(),
+                n => format!(
+                    "lsp-completion-on-accept %{{ lsp-completion-delete-suffix '<a-:>;l{}Ld' }}\n",
+                    n - 1
+                ),
+            };
+            let commit_characters = match &x.commit_characters {
+                Some(characters) if !characters.is_empty() => format!(
+                    "lsp-completion-commit-characters {}\n",
+                    editor_quote(
+                        &characters
+                            .iter()
+                            .map(|ch| format!("\\Q{}\\E", ch))
+                            .join("|")
+                    )
+                ),
+                _ => "".to_string(),
+            };
             let on_select = formatdoc!(
                 "lsp-completion-item-selected {completion_item_index}
-                 {maybe_resolve}info -markup -style menu -- %§{}§",
+                 {delete_suffix}{maybe_resolve}{commit_characters}info -markup -style menu -- %§{}§",
                 completion_menu_text(ctx.to_editor(), x).replace('§', "§§")
             );
 
-            let entry = match x.kind {
-                Some(k) => format!(
-                    "{}{} {{MenuInfo}}{:?}",
-                    escape_kakoune_markup(&x.label),
-                    " ".repeat(maxwidth - UnicodeWidthStr::width(x.label.as_str())),
-                    k
-                ),
-                None => escape_kakoune_markup(&x.label),
-            };
+            let entry = completion_menu_entry(x, maxwidth);
 
             let is_simple_text_edit = x.text_edit.as_ref().is_some_and(|cte| {
                 let document = match ctx.documents.get(&meta.buffile) {
@@ -184,7 +374,35 @@ fn editor_completion(
                         range.start.line == params.position.line
                             && range.end.line == params.position.line
                     }
-                    CompletionTextEdit::InsertAndReplace(_) => false,
+                    CompletionTextEdit::InsertAndReplace(edit) => {
+                        // Both ranges start at the same position, so this is a simple edit
+                        // unless either range leaves the cursor line.
+                        let insert = lsp_range_to_kakoune(
+                            &edit.insert,
+                            &document.text,
+                            server.offset_encoding,
+                        );
+                        let replace = lsp_range_to_kakoune(
+                            &edit.replace,
+                            &document.text,
+                            server.offset_encoding,
+                        );
+
+                        if can_infer_offset {
+                            match inferred_offset {
+                                None => inferred_offset = Some(insert.start.column),
+                                Some(offset) if offset != insert.start.column => {
+                                    can_infer_offset = false;
+                                    inferred_offset = None
+                                }
+                                _ => (),
+                            }
+                        };
+                        [insert, replace].iter().all(|range| {
+                            range.start.line == params.position.line
+                                && range.end.line == params.position.line
+                        })
+                    }
                 }
             });
             if !is_simple_text_edit {
@@ -214,31 +432,16 @@ fn editor_completion(
             // we'll need to perform some transformations on the completion commands.
             if ctx.config.snippet_support && x.insert_text_format == Some(InsertTextFormat::SNIPPET)
             {
-                lazy_static! {
-                    static ref SNIPPET_TABSTOP_RE: Regex = Regex::new(r"\$(?P<i>\d+)").unwrap();
-                    // {
-                    static ref SNIPPET_PLACEHOLDER_RE: Regex =
-                        Regex::new(r"\$\{(?P<i>\d+):?(?P<placeholder>[^}]+)\}").unwrap();
-                        // {
-                    static ref SNIPPET_ESCAPED_METACHARACTERS_RE: Regex =
-                        Regex::new(r"\\([$}\\,|])").unwrap();
-                }
-                let mut snippet = eventual_insert_text.to_string();
-                if !snippet.contains("$0") && !snippet.contains("${0") {
-                    snippet += "$0";
-                }
-                let insert_text = specified_insert_text;
-                let insert_text = SNIPPET_TABSTOP_RE.replace_all(insert_text, "");
-                let insert_text = SNIPPET_PLACEHOLDER_RE.replace_all(&insert_text, "$placeholder");
-                // Unescape metacharacters.
-                let insert_text = SNIPPET_ESCAPED_METACHARACTERS_RE.replace_all(&insert_text, "$1");
+                let snippet = snippet::expand(eventual_insert_text, &variables);
+                let insert_text = snippet::expand(specified_insert_text, &variables).text();
                 // There's some issue with multiline insert texts, and they also don't work well in the UI, so display on one line
                 let insert_text = insert_text.replace('\n', "");
 
                 let on_select = formatdoc!(
                     "{on_select}
-                     lsp-snippets-insert-completion {}",
-                    editor_quote(&snippet)
+                     lsp-snippets-insert-completion {} {}",
+                    editor_quote(&snippet.editor_snippet()),
+                    editor_quote(&snippet.editor_choices()),
                 );
 
                 completion_entry(&insert_text, &on_select, &entry)
@@ -267,16 +470,103 @@ fn editor_completion(
     let offset = inferred_offset.unwrap_or(params.completion.offset);
     let command = formatdoc!(
         "set-option window lsp_completions {line}.{offset}@{version} {items}
+         set-option window lsp_completions_start {line}.{offset}
          set-option window lsp_completions_timestamp {version}"
     );
     ctx.exec(meta, command);
 }
 
+fn label_detail(x: &CompletionItem) -> &str {
+    x.label_details
+        .as_ref()
+        .and_then(|details| details.detail.as_deref())
+        .unwrap_or_default()
+}
+
+fn label_description(x: &CompletionItem) -> &str {
+    x.label_details
+        .as_ref()
+        .and_then(|details| details.description.as_deref())
+        .unwrap_or_default()
+}
+
+fn label_width(x: &CompletionItem) -> usize {
+    UnicodeWidthStr::width(x.label.as_str()) + UnicodeWidthStr::width(label_detail(x))
+}
+
+fn is_deprecated(x: &CompletionItem) -> bool {
+    #[allow(deprecated)]
+    let deprecated = x.deprecated == Some(true);
+    deprecated
+        || x.tags
+            .as_ref()
+            .is_some_and(|tags| tags.contains(&CompletionItemTag::DEPRECATED))
+}
+
+/// The menu entry shows the label followed by its detail, then the description and kind
+/// aligned in a column.
+fn completion_menu_entry(x: &CompletionItem, maxwidth: usize) -> String {
+    let mut entry = String::new();
+    if is_deprecated(x) {
+        entry.push_str("{CompletionDeprecated}");
+    }
+    entry.push_str(&escape_kakoune_markup(&x.label));
+    let kind = x.kind.map(|kind| format!("{kind:?}")).unwrap_or_default();
+    let annotation = [label_description(x), &kind]
+        .iter()
+        .filter(|s| !s.is_empty())
+        .join(" ");
+    if label_detail(x).is_empty() && annotation.is_empty() {
+        return entry;
+    }
+    entry.push_str("{MenuInfo}");
+    entry.push_str(&escape_kakoune_markup(label_detail(x)));
+    if !annotation.is_empty() {
+        entry.push_str(&" ".repeat(maxwidth - label_width(x) + 1));
+        entry.push_str(&escape_kakoune_markup(&annotation));
+    }
+    entry
+}
+
+/// The number of characters after the cursor that are replaced when using the replace range.
+fn replaced_suffix_length(
+    edit: &InsertReplaceEdit,
+    text: &ropey::Rope,
+    offset_encoding: OffsetEncoding,
+) -> usize {
+    if edit.replace.end.line != edit.insert.end.line {
+        return 0;
+    }
+    let line = get_line(edit.insert.end.line as usize, text);
+    let char_index = |character: u32| {
+        lsp_character_to_byte_offset(line, character as usize, offset_encoding)
+            .map(|byte| line.byte_to_char(byte))
+    };
+    match (
+        char_index(edit.insert.end.character),
+        char_index(edit.replace.end.character),
+    ) {
+        (Some(insert_end), Some(replace_end)) => replace_end.saturating_sub(insert_end),
+        _ => 0,
+    }
+}
+
 fn completion_menu_text(to_editor: &ToEditorSender, x: &CompletionItem) -> String {
-    // Combine the 'detail' line and the full-text documentation into
-    // a single string. If both exist, separate them with a horizontal rule.
+    // Combine the label details, the 'detail' line and the full-text documentation into
+    // a single string. Separate the documentation from the rest with a horizontal rule.
     let mut markup = String::new();
 
+    if x.label_details.is_some() {
+        markup.push_str(&escape_kakoune_markup(
+            format!("{}{} {}", x.label, label_detail(x), label_description(x)).trim_end(),
+        ));
+        if x.detail.is_some() {
+            markup.push('\n');
+        } else if x.documentation.is_some() {
+            markup.push_str("\n\n---\n\n");
+        }
+    }
+
     if let Some(detail) = x.detail.as_ref() {
         markup.push_str(&escape_kakoune_markup(detail));
 
@@ -310,15 +600,18 @@ pub fn completion_item_resolve(
         pager_active,
     } = params;
 
-    if ctx.completion_last_client.is_none() || meta.client != ctx.completion_last_client {
+    let Some(client) = &meta.client else {
         return;
-    }
+    };
+    let Some(state) = ctx.completions.get(client) else {
+        return;
+    };
 
-    if completion_item_timestamp != ctx.completion_items_timestamp {
+    if completion_item_timestamp != state.items_timestamp {
         return;
     }
 
-    if completion_item_index >= isize::try_from(ctx.completion_items.len()).unwrap() {
+    if completion_item_index >= isize::try_from(state.items.len()).unwrap() {
         error!(
             ctx.to_editor(),
             "ignoring request to resolve completion item of invalid index {completion_item_index}"
@@ -327,7 +620,7 @@ pub fn completion_item_resolve(
     }
 
     let (server_id, item, detail, documentation) = if pager_active {
-        let (server_id, item) = &ctx.completion_items[completion_item_index as usize];
+        let (server_id, item) = &state.items[completion_item_index as usize];
         // Stop if there is nothing interesting to resolve.
         if item.detail.is_some() && item.documentation.is_some() {
             return;
@@ -339,12 +632,14 @@ pub fn completion_item_resolve(
             item.documentation.clone(),
         )
     } else {
-        // Since we're the only user of the completion items, we can clear them.
+        // Since we're the only user of the client's completion items, we can clear them. The
+        // next completion request must then ask the servers again.
         let (server_id, item) = ctx
-            .completion_items
-            .drain(..)
-            .nth(completion_item_index as usize)
-            .unwrap();
+            .completions
+            .remove(client)
+            .unwrap()
+            .items
+            .swap_remove(completion_item_index as usize);
 
         match item.additional_text_edits {
             Some(edits) if !edits.is_empty() => {
@@ -407,3 +702,53 @@ fn editor_completion_item_resolve(
         apply_text_edits(server_id, meta, uri, resolved_edits.clone(), ctx)
     }
 }
+
+/// Forget the completion state of a client that was closed.
+pub fn client_close(client: &ClientId, ctx: &mut Context) {
+    ctx.completions.remove(client);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completion_context_requeries_only_incomplete_lists() {
+        let start = ("/file.rs".to_string(), 1, 5);
+        let next_word = ("/file.rs".to_string(), 1, 7);
+        let complete: ServerId = 0;
+        let incomplete: ServerId = 1;
+        let trigger_characters = [".".to_string()];
+        let state = CompletionState {
+            start: Some(start.clone()),
+            incomplete: HashSet::from([incomplete]),
+            ..Default::default()
+        };
+        let context = |state, start, server_id, previous_char| {
+            completion_context(state, start, server_id, &trigger_characters, previous_char)
+                .map(|context| (context.trigger_kind, context.trigger_character))
+        };
+
+        // A new word is requested from every server.
+        assert_eq!(
+            context(None, &start, complete, Some('x')),
+            Some((CompletionTriggerKind::INVOKED, None))
+        );
+        assert_eq!(
+            context(Some(&state), &next_word, complete, Some('.')),
+            Some((
+                CompletionTriggerKind::TRIGGER_CHARACTER,
+                Some(".".to_string())
+            ))
+        );
+        // While typing the same word, complete lists are reused.
+        assert_eq!(context(Some(&state), &start, complete, Some('x')), None);
+        assert_eq!(
+            context(Some(&state), &start, incomplete, Some('.')),
+            Some((
+                CompletionTriggerKind::TRIGGER_FOR_INCOMPLETE_COMPLETIONS,
+                None
+            ))
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use lsp_types::*;
+
+use crate::types::*;
+
+fn sort_text(item: &CompletionItem) -> &str {
+    item.sort_text.as_ref().unwrap_or(&item.label)
+}
+
+fn insert_text(item: &CompletionItem) -> &str {
+    match &item.text_edit {
+        Some(CompletionTextEdit::Edit(edit)) => &edit.new_text,
+        Some(CompletionTextEdit::InsertAndReplace(edit)) => &edit.new_text,
+        None => item.insert_text.as_ref().unwrap_or(&item.label),
+    }
+}
+
+/// Score how well the typed text matches the item's filter text, or None if it is not a
+/// case-insensitive subsequence. Matches at word starts, consecutive matches, matches with the
+/// same case and prefix matches score higher.
+fn fuzzy_score(item: &CompletionItem, typed: &str) -> Option<i64> {
+    let filter_text: Vec<char> = item
+        .filter_text
+        .as_ref()
+        .unwrap_or(&item.label)
+        .chars()
+        .collect();
+    let mut score = 0;
+    let mut next = 0;
+    let mut previous_match = None;
+    for c in typed.chars() {
+        let index = (next..filter_text.len())
+            .find(|&i| filter_text[i].to_lowercase().eq(c.to_lowercase()))?;
+        let candidate = filter_text[index];
+        score += 1;
+        if previous_match.is_some_and(|previous| previous + 1 == index) {
+            score += 2;
+        }
+        let is_word_start = index == 0 || {
+            let before = filter_text[index - 1];
+            !before.is_alphanumeric() || (before.is_lowercase() && candidate.is_uppercase())
+        };
+        if is_word_start {
+            score += 3;
+        }
+        if candidate == c {
+            score += 1;
+        }
+        previous_match = Some(index);
+        next = index + 1;
+    }
+    if previous_match.is_some_and(|last| last + 1 == typed.chars().count()) {
+        // All typed characters are a prefix of the filter text.
+        score += 10;
+    }
+    Some(score)
+}
+
+/// Merge the completion items of all servers into the list shown in the menu, as indices into
+/// the given items.
+///
+/// Items are ordered by their fuzzy score against the typed text, then by the priority of their
+/// server, then by their sort text. Items with the same label and insert text are shown once.
+/// Items of servers in `filtered_servers` are dropped when they don't match the typed text; the
+/// other servers already filtered their lists. At most `max_items` items are kept, unless it is 0.
+pub fn rank(
+    items: &[(ServerId, CompletionItem)],
+    typed: &str,
+    filtered_servers: &HashSet<ServerId>,
+    priorities: &HashMap<ServerId, i32>,
+    max_items: usize,
+) -> Vec<usize> {
+    let ranked = items
+        .iter()
+        .enumerate()
+        .filter_map(|(index, (server_id, item))| {
+            let score = match fuzzy_score(item, typed) {
+                Some(score) => score,
+                None if filtered_servers.contains(server_id) => return None,
+                None => 0,
+            };
+            let priority = priorities.get(server_id).copied().unwrap_or_default();
+            Some((index, score, priority))
+        })
+        .sorted_by_key(|&(index, score, priority)| {
+            (
+                Reverse(score),
+                Reverse(priority),
+                sort_text(&items[index].1),
+            )
+        })
+        .map(|(index, _, _)| index)
+        .unique_by(|&index| {
+            let item = &items[index].1;
+            (item.label.as_str(), insert_text(item))
+        });
+    if max_items == 0 {
+        ranked.collect()
+    } else {
+        ranked.take(max_items).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(label: &str) -> CompletionItem {
+        CompletionItem {
+            label: label.to_string(),
+            ..CompletionItem::default()
+        }
+    }
+
+    #[test]
+    fn rank_merges_servers() {
+        let items = vec![
+            (0, item("format_args")),
+            (0, item("to_string")),
+            (1, item("to_string")),
+            (1, item("as_str")),
+            (1, item("str_len")),
+        ];
+        let priorities = HashMap::from([(1, 1)]);
+        let labels = |typed, filtered: &HashSet<ServerId>, max_items| {
+            rank(&items, typed, filtered, &priorities, max_items)
+                .into_iter()
+                .map(|index| (items[index].0, items[index].1.label.as_str()))
+                .collect::<Vec<_>>()
+        };
+
+        // Prefix matches rank first, duplicates are shown once from the server with the
+        // higher priority.
+        assert_eq!(
+            labels("str", &HashSet::new(), 0),
+            vec![
+                (1, "str_len"),
+                (1, "to_string"),
+                (1, "as_str"),
+                (0, "format_args")
+            ]
+        );
+        assert_eq!(
+            labels("str", &HashSet::from([0]), 2),
+            vec![(1, "str_len"), (1, "to_string")]
+        );
+        // Without typed text, the priority decides.
+        assert_eq!(labels("", &HashSet::new(), 0)[0].0, 1);
+    }
+}
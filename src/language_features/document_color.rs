@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use crate::capabilities::attempt_server_capability;
+use crate::capabilities::CAPABILITY_DOCUMENT_COLOR;
+use crate::context::*;
+use crate::position::*;
+use crate::text_edit::apply_text_edits_to_buffer;
+use crate::types::*;
+use crate::util::*;
+use itertools::Itertools;
+use lsp_types::request::*;
+use lsp_types::*;
+
+#[derive(Debug)]
+pub struct EditorColorPresentationParams {
+    pub position: KakounePosition,
+}
+
+/// Request the colors of the buffer, and call `f` with them, sorted by position.
+fn request_document_colors(
+    meta: EditorMeta,
+    ctx: &mut Context,
+    f: impl FnOnce(&mut Context, EditorMeta, Vec<(ServerId, ColorInformation)>) + 'static,
+) {
+    let eligible_servers: Vec<_> = ctx
+        .servers(&meta)
+        .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_DOCUMENT_COLOR))
+        .collect();
+    if eligible_servers.is_empty() {
+        return;
+    }
+
+    let req_params = eligible_servers
+        .into_iter()
+        .map(|(server_id, _server)| {
+            (
+                server_id,
+                vec![DocumentColorParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: file_path_to_uri(&meta.buffile),
+                    },
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                }],
+            )
+        })
+        .collect();
+    ctx.call::<DocumentColor, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx: &mut Context, meta, results| {
+            let mut colors: Vec<_> = results
+                .into_iter()
+                .flat_map(|(server_id, v)| v.into_iter().map(move |color| (server_id, color)))
+                .collect();
+            colors.sort_by_key(|(_, color)| color.range.start);
+            f(ctx, meta, colors)
+        },
+    );
+}
+
+pub fn text_document_document_color(meta: EditorMeta, ctx: &mut Context) {
+    request_document_colors(meta, ctx, |ctx, meta, colors| {
+        editor_document_colors(meta, colors, ctx)
+    });
+}
+
+/// Show a swatch of each color before its literal.
+fn editor_document_colors(
+    meta: EditorMeta,
+    colors: Vec<(ServerId, ColorInformation)>,
+    ctx: &mut Context,
+) {
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    let ranges = colors
+        .iter()
+        .map(|(server_id, info)| {
+            let server = ctx.server(*server_id);
+            let position =
+                lsp_position_to_kakoune(&info.range.start, &document.text, server.offset_encoding);
+            editor_quote(&format!(
+                "{position}+0|{{{}}}■{{Default}} ",
+                swatch_face(&info.color)
+            ))
+        })
+        .join(" ");
+    let command = format!(
+        "set-option buffer lsp_document_colors {} {}",
+        document.version, ranges
+    );
+    let command = format!(
+        "evaluate-commands -buffer {} {}",
+        editor_quote(&meta.buffile),
+        editor_quote(&command)
+    );
+    ctx.exec(meta, command);
+}
+
+/// The Kakoune face that draws a color as its foreground.
+fn swatch_face(color: &Color) -> String {
+    let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    let rgb = format!(
+        "{:02x}{:02x}{:02x}",
+        channel(color.red),
+        channel(color.green),
+        channel(color.blue)
+    );
+    if color.alpha < 1.0 {
+        format!("rgba:{rgb}{:02x}", channel(color.alpha))
+    } else {
+        format!("rgb:{rgb}")
+    }
+}
+
+pub fn text_document_color_presentation(
+    meta: EditorMeta,
+    params: EditorColorPresentationParams,
+    ctx: &mut Context,
+) {
+    request_document_colors(meta, ctx, move |ctx, meta, colors| {
+        let Some(document) = ctx.documents.get(&meta.buffile) else {
+            return;
+        };
+        let color = colors.into_iter().find(|(server_id, info)| {
+            let server = ctx.server(*server_id);
+            let range = lsp_range_to_kakoune(&info.range, &document.text, server.offset_encoding);
+            range.start <= params.position && params.position <= range.end
+        });
+        let Some((server_id, info)) = color else {
+            ctx.show_error(meta, "no color at cursor");
+            return;
+        };
+
+        let mut req_params = HashMap::new();
+        req_params.insert(
+            server_id,
+            vec![ColorPresentationParams {
+                text_document: TextDocumentIdentifier {
+                    uri: file_path_to_uri(&meta.buffile),
+                },
+                color: info.color,
+                range: info.range,
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+            }],
+        );
+        ctx.call::<ColorPresentationRequest, _>(
+            meta,
+            RequestParams::Each(req_params),
+            move |ctx: &mut Context, meta, results| {
+                let presentations = results
+                    .into_iter()
+                    .flat_map(|(_, presentations)| presentations)
+                    .collect();
+                editor_color_presentation(meta, server_id, info.range, presentations, ctx)
+            },
+        );
+    });
+}
+
+/// Offer the presentations of a color in a menu, each applying its edits.
+fn editor_color_presentation(
+    meta: EditorMeta,
+    server_id: ServerId,
+    range: Range,
+    presentations: Vec<ColorPresentation>,
+    ctx: &mut Context,
+) {
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    let server = ctx.server(server_id);
+    let mut choices: Vec<_> = presentations
+        .into_iter()
+        .filter_map(|presentation| {
+            // Without an edit, the label replaces the color.
+            let edit = match presentation.text_edit {
+                Some(edit) => edit,
+                None => TextEdit {
+                    range,
+                    new_text: presentation.label.clone(),
+                },
+            };
+            let edits = std::iter::once(edit)
+                .chain(presentation.additional_text_edits.unwrap_or_default())
+                .collect();
+            let command = apply_text_edits_to_buffer(
+                ctx.to_editor(),
+                &None,
+                None,
+                edits,
+                &document.text,
+                server.offset_encoding,
+                false,
+            )?;
+            Some((presentation.label, command))
+        })
+        .collect();
+    match choices.len() {
+        0 => ctx.show_error(meta, "no color presentations"),
+        1 => {
+            let (_, command) = choices.pop().unwrap();
+            ctx.exec(meta, command);
+        }
+        _ => {
+            let choices = choices
+                .into_iter()
+                .map(|(label, command)| {
+                    format!("{} {}", editor_quote(&label), editor_quote(&command))
+                })
+                .join(" ");
+            ctx.exec(meta, format!("lsp-menu {choices}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn swatch_face_converts_channels() {
+        let color = |red, green, blue, alpha| Color {
+            red,
+            green,
+            blue,
+            alpha,
+        };
+        assert_eq!(swatch_face(&color(1.0, 0.5, 0.0, 1.0)), "rgb:ff8000");
+        assert_eq!(swatch_face(&color(0.0, 0.0, 1.0, 0.5)), "rgba:0000ff80");
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+
+use crate::capabilities::attempt_server_capability;
+use crate::capabilities::CAPABILITY_DOCUMENT_LINK;
+use crate::context::*;
+use crate::position::*;
+use crate::types::*;
+use crate::util::*;
+use itertools::Itertools;
+use lsp_types::request::*;
+use lsp_types::*;
+use ropey::Rope;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DocumentLinkAction {
+    /// Choose a link to open from a menu.
+    Menu,
+    /// Open the link at the given position.
+    Open,
+    /// Highlight all links in the buffer.
+    Highlight,
+}
+
+#[derive(Debug)]
+pub struct EditorDocumentLinkParams {
+    pub action: DocumentLinkAction,
+    pub position: KakounePosition,
+}
+
+pub fn text_document_document_link(
+    meta: EditorMeta,
+    params: EditorDocumentLinkParams,
+    ctx: &mut Context,
+) {
+    let eligible_servers: Vec<_> = ctx
+        .servers(&meta)
+        .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_DOCUMENT_LINK))
+        .collect();
+    if eligible_servers.is_empty() {
+        return;
+    }
+
+    let req_params = eligible_servers
+        .into_iter()
+        .map(|(server_id, _server)| {
+            (
+                server_id,
+                vec![DocumentLinkParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: file_path_to_uri(&meta.buffile),
+                    },
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                }],
+            )
+        })
+        .collect();
+    ctx.call::<DocumentLinkRequest, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx: &mut Context, meta, results| {
+            let mut links: Vec<_> = results
+                .into_iter()
+                .flat_map(|(server_id, v)| {
+                    v.unwrap_or_default()
+                        .into_iter()
+                        .map(move |link| (server_id, link))
+                })
+                .collect();
+            links.sort_by_key(|(_, link)| link.range.start);
+            match params.action {
+                DocumentLinkAction::Menu => editor_document_links_menu(meta, links, ctx),
+                DocumentLinkAction::Open => open_link_at(meta, params.position, links, ctx),
+                DocumentLinkAction::Highlight => editor_document_links(meta, links, ctx),
+            }
+        },
+    );
+}
+
+fn editor_document_links(
+    meta: EditorMeta,
+    links: Vec<(ServerId, DocumentLink)>,
+    ctx: &mut Context,
+) {
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    let ranges = links
+        .iter()
+        .filter(|(_, link)| link.range.start != link.range.end)
+        .map(|(server_id, link)| {
+            let server = ctx.server(*server_id);
+            let range = lsp_range_to_kakoune(&link.range, &document.text, server.offset_encoding);
+            format!("{}|DocumentLink", ForwardKakouneRange(range))
+        })
+        .join(" ");
+    let command = format!(
+        "set-option buffer lsp_document_links {} {}",
+        document.version, ranges
+    );
+    ctx.exec(meta, command);
+}
+
+fn editor_document_links_menu(
+    meta: EditorMeta,
+    links: Vec<(ServerId, DocumentLink)>,
+    ctx: &mut Context,
+) {
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    if links.is_empty() {
+        ctx.show_error(meta, "no document links");
+        return;
+    }
+    let choices = links
+        .iter()
+        .map(|(server_id, link)| {
+            let server = ctx.server(*server_id);
+            let start =
+                lsp_position_to_kakoune(&link.range.start, &document.text, server.offset_encoding);
+            let description = match (&link.target, &link.tooltip) {
+                (Some(target), _) => target.as_str().to_string(),
+                (None, Some(tooltip)) => tooltip.clone(),
+                (None, None) => link_text(&link.range, &document.text, server.offset_encoding),
+            };
+            // Links without a target are resolved when chosen.
+            let command = match &link.target {
+                Some(target) => open_uri_command(target),
+                None => format!("lsp-open-link-at {} {}", start.line, start.column),
+            };
+            format!(
+                "{} {}",
+                editor_quote(&format!("{}:{} {}", start.line, start.column, description)),
+                editor_quote(&command)
+            )
+        })
+        .join(" ");
+    ctx.exec(meta, format!("lsp-menu {choices}"));
+}
+
+fn open_link_at(
+    meta: EditorMeta,
+    position: KakounePosition,
+    links: Vec<(ServerId, DocumentLink)>,
+    ctx: &mut Context,
+) {
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    let link = links.into_iter().find(|(server_id, link)| {
+        let server = ctx.server(*server_id);
+        let range = lsp_range_to_kakoune(&link.range, &document.text, server.offset_encoding);
+        range.start <= position && position <= range.end
+    });
+    let Some((server_id, link)) = link else {
+        ctx.show_error(meta, "no link at cursor");
+        return;
+    };
+
+    let can_resolve = ctx
+        .server(server_id)
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.document_link_provider.as_ref())
+        .and_then(|provider| provider.resolve_provider)
+        .unwrap_or(false);
+    if link.target.is_none() && can_resolve {
+        let mut req_params = HashMap::new();
+        req_params.insert(server_id, vec![link]);
+        ctx.call::<DocumentLinkResolve, _>(
+            meta,
+            RequestParams::Each(req_params),
+            |ctx: &mut Context, meta, results| {
+                if let Some((_, link)) = results.into_iter().next() {
+                    open_link(meta, &link, ctx);
+                }
+            },
+        );
+        return;
+    }
+    open_link(meta, &link, ctx);
+}
+
+fn open_link(meta: EditorMeta, link: &DocumentLink, ctx: &mut Context) {
+    match &link.target {
+        Some(target) => {
+            let command = open_uri_command(target);
+            ctx.exec(meta, command);
+        }
+        None => ctx.show_error(meta, "document link has no target"),
+    }
+}
+
+/// The command to open a URI: files are edited in the jump client, other URIs are passed to
+/// the configured opener.
+pub fn open_uri_command(uri: &Uri) -> String {
+    let file = url::Url::parse(uri.as_str())
+        .ok()
+        .filter(|url| url.scheme() == "file")
+        .and_then(|url| {
+            let path = url.to_file_path().ok()?;
+            Some((path, url.fragment().and_then(fragment_position)))
+        });
+    let Some((path, position)) = file else {
+        return format!("lsp-open-external {}", editor_quote(uri.as_str()));
+    };
+    let mut command = format!(
+        "edit -existing -- {}",
+        editor_quote(&path.to_string_lossy())
+    );
+    if let Some((line, column)) = position {
+        command.push_str(&format!(" {line} {column}"));
+    }
+    format!(
+        "evaluate-commands -try-client %opt{{jumpclient}} -- {}",
+        editor_quote(&command)
+    )
+}
+
+/// Parse a line and optional column from a URI fragment like "L12" or "L12,5".
+fn fragment_position(fragment: &str) -> Option<(u32, u32)> {
+    let fragment = fragment.strip_prefix('L')?;
+    let (line, column) = match fragment.split_once(',') {
+        Some((line, column)) => (line, Some(column)),
+        None => (fragment, None),
+    };
+    let line = line.parse().ok()?;
+    let column = match column {
+        Some(column) => column.parse().ok()?,
+        None => 1,
+    };
+    Some((line, column))
+}
+
+/// The text of a link, up to the end of its first line.
+fn link_text(range: &Range, text: &Rope, offset_encoding: OffsetEncoding) -> String {
+    let line = get_line(range.start.line as usize, text);
+    let start = lsp_character_to_byte_offset(line, range.start.character as usize, offset_encoding)
+        .unwrap_or(0);
+    let end = if range.end.line == range.start.line {
+        lsp_character_to_byte_offset(line, range.end.character as usize, offset_encoding)
+    } else {
+        None
+    }
+    .unwrap_or(line.len_bytes());
+    line.byte_slice(start..end.max(start))
+        .to_string()
+        .trim_end()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragment_position_parses_line_and_column() {
+        assert_eq!(fragment_position("L12"), Some((12, 1)));
+        assert_eq!(fragment_position("L12,5"), Some((12, 5)));
+        assert_eq!(fragment_position("section"), None);
+        assert_eq!(fragment_position("L12,x"), None);
+    }
+}
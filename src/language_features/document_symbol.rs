@@ -585,7 +585,7 @@ fn next_or_prev_symbol_details<T: Symbol<T> + 'static>(
 ) -> Option<(String, KakounePosition, String, SymbolKind)> {
     // Some language servers return symbol locations that are not sorted in ascending order.
     // Sort the results so we can find next and previous properly.
-    items.sort_by(|a, b| a.selection_range().start.cmp(&b.selection_range().start));
+    items.sort_by_key(|a| a.selection_range().start);
 
     // Setup an iterator dependending on whether we are searching forwards or backwards
     let it: Box<dyn Iterator<Item = &mut T>> = if params.search_next {
@@ -769,12 +769,6 @@ fn editor_object(
 ) {
     let (server_id, result) = result;
 
-    let selections: Vec<(KakouneRange, KakounePosition)> = params
-        .selections_desc
-        .iter()
-        .map(|s| parse_kakoune_range(s))
-        .collect();
-
     let mut symbol_kinds_query: Vec<SymbolKind> = vec![];
     for kind_str in &params.symbol_kinds {
         symbol_kinds_query.push(match symbol_kind_from_string(kind_str) {
@@ -796,7 +790,7 @@ fn editor_object(
         }
     };
     let server = ctx.server(server_id);
-    let mut ranges = match result {
+    let ranges = match result {
         None => return,
         Some(DocumentSymbolResponse::Flat(symbols)) => {
             flat_symbol_ranges(server, document, symbols, symbol_kinds_query)
@@ -811,9 +805,48 @@ fn editor_object(
         return;
     }
 
-    let mode = params.mode;
-    let forward = !["[", "{"].contains(&mode.as_str());
-    let surround = ["<a-i>", "<a-a>"].contains(&mode.as_str());
+    select_object(
+        meta,
+        "lsp-object",
+        &params.mode,
+        params.count,
+        &params.selections_desc,
+        ranges,
+        server_id,
+        ctx,
+    );
+}
+
+/// Select the object adjacent to or surrounding each selection, as requested by an object-mode
+/// command. Each range is given twice, once paired with its start and once with its end.
+#[allow(clippy::too_many_arguments)]
+pub fn select_object(
+    meta: EditorMeta,
+    command: &str,
+    mode: &str,
+    count: u32,
+    selections_desc: &[String],
+    mut ranges: Vec<(KakouneRange, KakounePosition)>,
+    server_id: ServerId,
+    ctx: &mut Context,
+) {
+    let selections: Vec<(KakouneRange, KakounePosition)> = selections_desc
+        .iter()
+        .map(|s| parse_kakoune_range(s))
+        .collect();
+
+    let document = match ctx.documents.get(&meta.buffile) {
+        Some(document) => document,
+        None => {
+            let err = format!("Missing document for {}", &meta.buffile);
+            ctx.show_error(meta, err);
+            return;
+        }
+    };
+    let server = ctx.server(server_id);
+
+    let forward = !["[", "{"].contains(&mode);
+    let surround = ["<a-i>", "<a-a>"].contains(&mode);
 
     ranges.sort_by_key(|range| {
         let start = range.1;
@@ -838,7 +871,7 @@ fn editor_object(
             }
         }
 
-        let mut count = params.count.max(1);
+        let mut count = count.max(1);
         let mut cur = cursor;
         let mut i = 0;
         let sym_range = loop {
@@ -892,21 +925,21 @@ fn editor_object(
         let sel_min = selection.start.min(selection.end);
         let sym_start = sym_range.start;
         let sym_end = sym_range.end;
-        let (start, end) = match mode.as_str() {
+        let (start, end) = match mode {
             "<a-i>" | "<a-a>" => (sym_start, sym_end),
             "[" => (cursor.min(sym_end), sym_start),
             "]" => (cursor.max(sym_start), sym_end),
             "{" => (sel_max, sym_start),
             "}" => (sel_min, sym_end),
             _ => {
-                ctx.show_error(meta, "lsp-object: invalid mode");
+                ctx.show_error(meta, format!("{command}: invalid mode"));
                 return;
             }
         };
         new_selections.push(KakouneRange { start, end })
     }
     if new_selections.is_empty() {
-        ctx.show_error(meta, "lsp-object: no selections remaining");
+        ctx.show_error(meta, format!("{command}: no selections remaining"));
         return;
     }
     ctx.exec(
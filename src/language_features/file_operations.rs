@@ -0,0 +1,579 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::context::*;
+use crate::edit_preview;
+use crate::text_sync::{text_document_did_close, text_document_did_open};
+use crate::types::*;
+use crate::util::*;
+use lsp_types::notification::*;
+use lsp_types::request::*;
+use lsp_types::*;
+
+/// A file operation requested by the user. The editor sends absolute paths, which are only
+/// normalized here.
+#[derive(Debug)]
+pub enum FileOperation {
+    Create { path: String },
+    Rename { old_path: String, new_path: String },
+    Delete { path: String },
+}
+
+impl FileOperation {
+    /// The path that the servers' filters are matched against.
+    fn path(&self) -> &str {
+        match self {
+            FileOperation::Create { path } => path,
+            FileOperation::Rename { old_path, .. } => old_path,
+            FileOperation::Delete { path } => path,
+        }
+    }
+
+    fn verb(&self) -> &'static str {
+        match self {
+            FileOperation::Create { .. } => "create",
+            FileOperation::Rename { .. } => "rename",
+            FileOperation::Delete { .. } => "delete",
+        }
+    }
+}
+
+/// Ask the servers for edits to make before a file operation, such as fixing imports, apply
+/// them and let the editor perform the operation.
+pub fn will_file_operation(meta: EditorMeta, operation: FileOperation, ctx: &mut Context) {
+    let directory = Path::new(&meta.buffile)
+        .parent()
+        .unwrap_or(Path::new("/"))
+        .to_path_buf();
+    let resolve = |path: &str| absolute_path(&directory, path);
+    let operation = match operation {
+        FileOperation::Create { path } => FileOperation::Create {
+            path: resolve(&path),
+        },
+        FileOperation::Rename { old_path, new_path } => FileOperation::Rename {
+            old_path: resolve(&old_path),
+            new_path: resolve(&new_path),
+        },
+        FileOperation::Delete { path } => FileOperation::Delete {
+            path: resolve(&path),
+        },
+    };
+    let target_exists = match &operation {
+        FileOperation::Create { path } => Some(path),
+        FileOperation::Rename { new_path, .. } => Some(new_path),
+        FileOperation::Delete { .. } => None,
+    }
+    .filter(|path| Path::new(path).exists());
+    if let Some(path) = target_exists {
+        ctx.show_error(meta, format!("file already exists: {path}"));
+        return;
+    }
+
+    let is_folder = Path::new(operation.path()).is_dir();
+    let servers: Vec<_> = ctx
+        .servers(&meta)
+        .filter(|(_, server)| {
+            let registration =
+                file_operations_capabilities(server).and_then(|caps| match &operation {
+                    FileOperation::Create { .. } => caps.will_create.as_ref(),
+                    FileOperation::Rename { .. } => caps.will_rename.as_ref(),
+                    FileOperation::Delete { .. } => caps.will_delete.as_ref(),
+                });
+            matches_filters(registration, operation.path(), is_folder)
+        })
+        .map(|(server_id, _)| server_id)
+        .collect();
+
+    let commit = match &operation {
+        FileOperation::Create { path } => {
+            format!("lsp-send workspace/didCreateFiles {}", editor_quote(path))
+        }
+        // Send it from the buffer, so its pending changes are synced before the document moves.
+        FileOperation::Rename { old_path, new_path } => format!(
+            "evaluate-commands -buffer {} {}",
+            editor_quote(old_path),
+            editor_quote(&format!(
+                "lsp-send workspace/didRenameFiles {} {}",
+                editor_quote(old_path),
+                editor_quote(new_path)
+            ))
+        ),
+        FileOperation::Delete { path } => {
+            format!("lsp-send workspace/didDeleteFiles {}", editor_quote(path))
+        }
+    };
+    if servers.is_empty() {
+        ctx.exec(meta, commit);
+        return;
+    }
+
+    match &operation {
+        FileOperation::Create { path } => request_edits::<WillCreateFiles>(
+            meta,
+            servers,
+            CreateFilesParams {
+                files: vec![FileCreate { uri: uri(path) }],
+            },
+            commit,
+            ctx,
+        ),
+        FileOperation::Rename { old_path, new_path } => request_edits::<WillRenameFiles>(
+            meta,
+            servers,
+            RenameFilesParams {
+                files: vec![FileRename {
+                    old_uri: uri(old_path),
+                    new_uri: uri(new_path),
+                }],
+            },
+            commit,
+            ctx,
+        ),
+        FileOperation::Delete { path } => request_edits::<WillDeleteFiles>(
+            meta,
+            servers,
+            DeleteFilesParams {
+                files: vec![FileDelete { uri: uri(path) }],
+            },
+            commit,
+            ctx,
+        ),
+    }
+}
+
+/// Ask the servers for edits to apply before the operation, and perform it once they were
+/// applied together.
+fn request_edits<R>(
+    meta: EditorMeta,
+    servers: Vec<ServerId>,
+    params: R::Params,
+    commit: String,
+    ctx: &mut Context,
+) where
+    R: lsp_types::request::Request<Result = Option<WorkspaceEdit>>,
+    R::Params: IntoParams + Clone,
+{
+    let req_params = servers
+        .into_iter()
+        .map(|server_id| (server_id, vec![params.clone()]))
+        .collect();
+    ctx.call::<R, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx: &mut Context, meta, results| {
+            let edits: Vec<_> = results
+                .into_iter()
+                .filter_map(|(server_id, edit)| Some((server_id, edit?)))
+                .collect();
+            let Some(&(server_id, _)) = edits.first() else {
+                ctx.exec(meta, commit);
+                return;
+            };
+            // The merged edit is applied with the offset encoding of a single server.
+            let offset_encoding = ctx.server(server_id).offset_encoding;
+            if edits
+                .iter()
+                .any(|(server_id, _)| ctx.server(*server_id).offset_encoding != offset_encoding)
+            {
+                ctx.show_error(
+                    meta,
+                    "not performing file operation: servers with different offset encodings \
+                     sent edits for it",
+                );
+                return;
+            }
+            let edit = merge_workspace_edits(edits.into_iter().map(|(_, edit)| edit));
+            // A failure is already reported; the operation is not performed without its edits.
+            edit_preview::apply_or_preview_edit(
+                server_id,
+                meta,
+                None,
+                None,
+                edit,
+                Some(commit),
+                ctx,
+            );
+        },
+    );
+}
+
+/// Combine the edits of several servers. Their document changes are concatenated, so they are
+/// applied in order.
+fn merge_workspace_edits(edits: impl IntoIterator<Item = WorkspaceEdit>) -> WorkspaceEdit {
+    let edits: Vec<_> = edits.into_iter().collect();
+    let mut merged = WorkspaceEdit::default();
+    for edit in &edits {
+        if let Some(annotations) = &edit.change_annotations {
+            merged
+                .change_annotations
+                .get_or_insert_with(Default::default)
+                .extend(annotations.clone());
+        }
+    }
+    if edits.iter().all(|edit| edit.document_changes.is_none()) {
+        for edit in edits {
+            for (uri, text_edits) in edit.changes.unwrap_or_default() {
+                merged
+                    .changes
+                    .get_or_insert_with(Default::default)
+                    .entry(uri)
+                    .or_default()
+                    .extend(text_edits);
+            }
+        }
+        return merged;
+    }
+    let text_document_edit = |uri, edits: Vec<TextEdit>| {
+        DocumentChangeOperation::Edit(TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier { uri, version: None },
+            edits: edits.into_iter().map(OneOf::Left).collect(),
+        })
+    };
+    // A server's changes are only used if it sent no document changes.
+    let mut operations = vec![];
+    for edit in edits {
+        match edit.document_changes {
+            Some(DocumentChanges::Edits(edits)) => {
+                operations.extend(edits.into_iter().map(DocumentChangeOperation::Edit))
+            }
+            Some(DocumentChanges::Operations(ops)) => operations.extend(ops),
+            None => operations.extend(
+                edit.changes
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(uri, edits)| text_document_edit(uri, edits)),
+            ),
+        }
+    }
+    merged.document_changes = Some(DocumentChanges::Operations(operations));
+    merged
+}
+
+/// Perform a file operation on disk, update the editor and the documents, and notify the
+/// servers. The editor is only changed once the operation succeeded on disk.
+pub fn did_file_operation(meta: EditorMeta, operation: FileOperation, ctx: &mut Context) {
+    // Deleted or moved paths are no longer folders.
+    let is_folder = Path::new(operation.path()).is_dir();
+    let result = match &operation {
+        FileOperation::Create { path } => create_parent(path).and_then(|()| {
+            fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map(|_| ())
+        }),
+        FileOperation::Rename { old_path, new_path } => {
+            // The renamed buffer may not have been written yet.
+            if Path::new(old_path).exists() {
+                create_parent(new_path).and_then(|()| fs::rename(old_path, new_path))
+            } else {
+                Ok(())
+            }
+        }
+        FileOperation::Delete { path } => {
+            if Path::new(path).is_dir() {
+                fs::remove_dir_all(path)
+            } else {
+                fs::remove_file(path)
+            }
+        }
+    };
+    if let Err(err) = result {
+        let message = format!("failed to {} {}: {err}", operation.verb(), operation.path());
+        ctx.show_error(meta, message);
+        return;
+    }
+
+    let servers: Vec<_> = ctx
+        .servers(&meta)
+        .filter(|(_, server)| {
+            let registration =
+                file_operations_capabilities(server).and_then(|caps| match &operation {
+                    FileOperation::Create { .. } => caps.did_create.as_ref(),
+                    FileOperation::Rename { .. } => caps.did_rename.as_ref(),
+                    FileOperation::Delete { .. } => caps.did_delete.as_ref(),
+                });
+            matches_filters(registration, operation.path(), is_folder)
+        })
+        .map(|(server_id, _)| server_id)
+        .collect();
+
+    match operation {
+        FileOperation::Create { path } => {
+            ctx.exec(meta, format!("edit -existing -- {}", editor_quote(&path)));
+            let params = CreateFilesParams {
+                files: vec![FileCreate { uri: uri(&path) }],
+            };
+            for server_id in servers {
+                ctx.notify::<DidCreateFiles>(server_id, params.clone());
+            }
+        }
+        FileOperation::Rename { old_path, new_path } => {
+            ctx.exec(
+                meta.clone(),
+                format!(
+                    "evaluate-commands -buffer {} {}",
+                    editor_quote(&old_path),
+                    editor_quote(&format!("rename-buffer -file {}", editor_quote(&new_path)))
+                ),
+            );
+            rename_document(&meta, &old_path, &new_path, ctx);
+            let params = RenameFilesParams {
+                files: vec![FileRename {
+                    old_uri: uri(&old_path),
+                    new_uri: uri(&new_path),
+                }],
+            };
+            for server_id in servers {
+                ctx.notify::<DidRenameFiles>(server_id, params.clone());
+            }
+        }
+        FileOperation::Delete { path } => {
+            let params = DeleteFilesParams {
+                files: vec![FileDelete { uri: uri(&path) }],
+            };
+            for server_id in servers {
+                ctx.notify::<DidDeleteFiles>(server_id, params.clone());
+            }
+            // Closing the buffer sends textDocument/didClose.
+            ctx.exec(
+                meta,
+                format!(
+                    "try %{{ evaluate-commands -buffer {} delete-buffer! }}",
+                    editor_quote(&path)
+                ),
+            );
+        }
+    }
+}
+
+/// Close the document under its old name, and open it under the new name of the buffer.
+fn rename_document(meta: &EditorMeta, old_path: &str, new_path: &str, ctx: &mut Context) {
+    let Some(document) = ctx.documents.get(old_path) else {
+        return;
+    };
+    let draft = document.text.to_string();
+    // Changes since the last sync are sent once the buffer changes again.
+    let version = document.version;
+    let old_meta = EditorMeta {
+        buffile: old_path.to_string(),
+        ..meta.clone()
+    };
+    text_document_did_close(old_meta, ctx);
+    let new_meta = EditorMeta {
+        buffile: new_path.to_string(),
+        version,
+        ..meta.clone()
+    };
+    text_document_did_open(new_meta, TextDocumentDidOpenParams { draft }, ctx);
+}
+
+/// Record the filters of a file operation that a server registered dynamically.
+pub fn register_file_operation(
+    server_id: ServerId,
+    method: &str,
+    options: FileOperationRegistrationOptions,
+    ctx: &mut Context,
+) {
+    let capabilities = ctx
+        .language_servers
+        .get_mut(&server_id)
+        .unwrap()
+        .capabilities
+        .as_mut()
+        .unwrap();
+    let file_operations = capabilities
+        .workspace
+        .get_or_insert_with(Default::default)
+        .file_operations
+        .get_or_insert_with(Default::default);
+    let registration = match method {
+        WillCreateFiles::METHOD => &mut file_operations.will_create,
+        WillRenameFiles::METHOD => &mut file_operations.will_rename,
+        WillDeleteFiles::METHOD => &mut file_operations.will_delete,
+        DidCreateFiles::METHOD => &mut file_operations.did_create,
+        DidRenameFiles::METHOD => &mut file_operations.did_rename,
+        DidDeleteFiles::METHOD => &mut file_operations.did_delete,
+        _ => return,
+    };
+    match registration {
+        Some(registration) => registration.filters.extend(options.filters),
+        None => *registration = Some(options),
+    }
+}
+
+fn file_operations_capabilities(
+    server: &ServerSettings,
+) -> Option<&WorkspaceFileOperationsServerCapabilities> {
+    server
+        .capabilities
+        .as_ref()?
+        .workspace
+        .as_ref()?
+        .file_operations
+        .as_ref()
+}
+
+/// Whether a path matches any of the filters a server registered for an operation.
+fn matches_filters(
+    registration: Option<&FileOperationRegistrationOptions>,
+    path: &str,
+    is_folder: bool,
+) -> bool {
+    let Some(registration) = registration else {
+        return false;
+    };
+    registration.filters.iter().any(|filter| {
+        if filter
+            .scheme
+            .as_ref()
+            .is_some_and(|scheme| scheme != "file")
+        {
+            return false;
+        }
+        let pattern = &filter.pattern;
+        match pattern.matches {
+            Some(FileOperationPatternKind::File) if is_folder => return false,
+            Some(FileOperationPatternKind::Folder) if !is_folder => return false,
+            _ => (),
+        }
+        let ignore_case = pattern
+            .options
+            .as_ref()
+            .and_then(|options| options.ignore_case)
+            .unwrap_or(false);
+        let options = glob::MatchOptions {
+            case_sensitive: !ignore_case,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        expand_braces(&pattern.glob)
+            .iter()
+            .any(|glob| glob::Pattern::new(glob).is_ok_and(|glob| glob.matches_with(path, options)))
+    })
+}
+
+/// Expand the alternatives in braces of a glob pattern, which the glob crate doesn't support.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let Some(open) = pattern.find('{') else {
+        return vec![pattern.to_string()];
+    };
+    let mut depth = 0;
+    let mut alternatives = vec![];
+    let mut start = open + 1;
+    for (index, c) in pattern[open..].char_indices() {
+        let index = open + index;
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    alternatives.push(&pattern[start..index]);
+                    let (prefix, suffix) = (&pattern[..open], &pattern[index + 1..]);
+                    return alternatives
+                        .into_iter()
+                        .flat_map(|alternative| {
+                            expand_braces(&format!("{prefix}{alternative}{suffix}"))
+                        })
+                        .collect();
+                }
+            }
+            ',' if depth == 1 => {
+                alternatives.push(&pattern[start..index]);
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+    // Unbalanced braces are matched literally.
+    vec![pattern.to_string()]
+}
+
+fn absolute_path(directory: &Path, path: &str) -> String {
+    let mut absolute = PathBuf::new();
+    for component in directory.join(path).components() {
+        match component {
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            Component::CurDir => (),
+            component => absolute.push(component),
+        }
+    }
+    absolute.to_string_lossy().into_owned()
+}
+
+fn create_parent(path: &str) -> io::Result<()> {
+    match Path::new(path).parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+fn uri(path: &str) -> String {
+    file_path_to_uri(path).as_str().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn merge_workspace_edits_keeps_every_servers_changes() {
+        let uri = |path: &str| file_path_to_uri(path);
+        let text_edit = |new_text: &str| TextEdit {
+            range: Range::default(),
+            new_text: new_text.to_string(),
+        };
+        let changes = |path: &str, new_text: &str| WorkspaceEdit {
+            changes: Some(HashMap::from([(uri(path), vec![text_edit(new_text)])])),
+            ..Default::default()
+        };
+
+        let merged = merge_workspace_edits([changes("/a", "1"), changes("/a", "2")]);
+        assert_eq!(
+            merged.changes.unwrap()[&uri("/a")],
+            [text_edit("1"), text_edit("2")]
+        );
+
+        let rename = DocumentChangeOperation::Op(ResourceOp::Rename(RenameFile {
+            old_uri: uri("/b"),
+            new_uri: uri("/c"),
+            options: None,
+            annotation_id: None,
+        }));
+        let operations = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Operations(vec![rename.clone()])),
+            ..Default::default()
+        };
+        let merged = merge_workspace_edits([changes("/a", "1"), operations]);
+        let Some(DocumentChanges::Operations(ops)) = merged.document_changes else {
+            panic!("expected operations");
+        };
+        assert_eq!(
+            ops,
+            [
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier {
+                        uri: uri("/a"),
+                        version: None,
+                    },
+                    edits: vec![OneOf::Left(text_edit("1"))],
+                }),
+                rename,
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_braces_expands_nested_alternatives() {
+        assert_eq!(expand_braces("**/*.rs"), vec!["**/*.rs"]);
+        assert_eq!(
+            expand_braces("**/*.{ts,js{,x}}"),
+            vec!["**/*.ts", "**/*.js", "**/*.jsx"]
+        );
+        assert_eq!(expand_braces("src/{a"), vec!["src/{a"]);
+    }
+}
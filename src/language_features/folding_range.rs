@@ -0,0 +1,232 @@
+use crate::capabilities::{attempt_server_capability, CAPABILITY_FOLDING_RANGE};
+use crate::context::*;
+use crate::language_features::document_symbol::select_object;
+use crate::position::*;
+use crate::types::*;
+use crate::util::*;
+use itertools::Itertools;
+use lsp_types::request::*;
+use lsp_types::*;
+
+#[derive(Clone, Debug)]
+pub struct FoldObjectParams {
+    pub count: u32,
+    pub mode: String,
+    pub selections_desc: Vec<String>,
+    pub fold_kinds: Vec<String>,
+}
+
+/// Request folding ranges from the first eligible server, and pass the result to the callback.
+fn request_folding_ranges<F>(meta: EditorMeta, ctx: &mut Context, callback: F)
+where
+    F: FnOnce(&mut Context, EditorMeta, (ServerId, Vec<FoldingRange>)) + 'static,
+{
+    let eligible_servers: Vec<_> = ctx
+        .servers(&meta)
+        .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_FOLDING_RANGE))
+        .collect();
+    let req_params = eligible_servers
+        .into_iter()
+        .map(|(server_id, _)| {
+            (
+                server_id,
+                vec![FoldingRangeParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: file_path_to_uri(&meta.buffile),
+                    },
+                    work_done_progress_params: Default::default(),
+                    partial_result_params: Default::default(),
+                }],
+            )
+        })
+        .collect();
+    ctx.call::<FoldingRangeRequest, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx: &mut Context, meta, results| {
+            let Some((server_id, Some(ranges))) = results.into_iter().find(|(_, v)| v.is_some())
+            else {
+                return;
+            };
+            callback(ctx, meta, (server_id, ranges))
+        },
+    );
+}
+
+pub fn folding_ranges(meta: EditorMeta, ctx: &mut Context) {
+    request_folding_ranges(meta, ctx, |ctx, meta, result| {
+        editor_folding_ranges(meta, result, ctx)
+    });
+}
+
+pub fn fold_object(meta: EditorMeta, params: FoldObjectParams, ctx: &mut Context) {
+    let mut fold_kinds = vec![];
+    for kind in &params.fold_kinds {
+        match fold_kind_from_string(kind) {
+            Some(kind) => fold_kinds.push(kind),
+            None => {
+                let err = format!("invalid fold kind '{}'", kind);
+                ctx.show_error(meta, err);
+                return;
+            }
+        }
+    }
+    request_folding_ranges(meta, ctx, move |ctx, meta, (server_id, folds)| {
+        let Some(document) = ctx.documents.get(&meta.buffile) else {
+            return;
+        };
+        let server = ctx.server(server_id);
+        let ranges: Vec<_> = folds
+            .iter()
+            .filter(|fold| {
+                fold_kinds.is_empty()
+                    || fold
+                        .kind
+                        .as_ref()
+                        .is_some_and(|kind| fold_kinds.contains(kind))
+            })
+            .flat_map(|fold| {
+                let range = fold_range_to_kakoune(fold, &document.text, server.offset_encoding);
+                [(range, range.start), (range, range.end)]
+            })
+            .collect();
+        if ranges.is_empty() {
+            ctx.show_error(meta, "lsp-fold-object: no matching fold found");
+            return;
+        }
+        select_object(
+            meta,
+            "lsp-fold-object",
+            &params.mode,
+            params.count,
+            &params.selections_desc,
+            ranges,
+            server_id,
+            ctx,
+        );
+    });
+}
+
+fn fold_kind_from_string(value: &str) -> Option<FoldingRangeKind> {
+    match value {
+        "comment" => Some(FoldingRangeKind::Comment),
+        "imports" => Some(FoldingRangeKind::Imports),
+        "region" => Some(FoldingRangeKind::Region),
+        _ => None,
+    }
+}
+
+fn fold_kind_label(kind: Option<&FoldingRangeKind>) -> &'static str {
+    match kind {
+        Some(FoldingRangeKind::Comment) => "comment",
+        Some(FoldingRangeKind::Imports) => "imports",
+        Some(FoldingRangeKind::Region) => "region",
+        None => "block",
+    }
+}
+
+/// Folds are treated as whole lines, since we advertise lineFoldingOnly.
+fn fold_range_to_kakoune(
+    fold: &FoldingRange,
+    text: &ropey::Rope,
+    offset_encoding: OffsetEncoding,
+) -> KakouneRange {
+    let range = Range {
+        start: Position {
+            line: fold.start_line,
+            character: 0,
+        },
+        end: Position {
+            line: fold.end_line + 1,
+            character: 0,
+        },
+    };
+    lsp_range_to_kakoune(&range, text, offset_encoding)
+}
+
+fn editor_folding_ranges(
+    meta: EditorMeta,
+    (server_id, mut folds): (ServerId, Vec<FoldingRange>),
+    ctx: &mut Context,
+) {
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    let server = ctx.server(server_id);
+    folds.sort_by_key(|fold| {
+        (
+            fold_kind_label(fold.kind.as_ref()),
+            fold.start_line,
+            fold.end_line,
+        )
+    });
+    let filename = short_file_path(&meta.buffile, ctx.main_root(&meta));
+    let contents = folds
+        .iter()
+        .map(|fold| {
+            let range = fold_range_to_kakoune(fold, &document.text, server.offset_encoding);
+            let first_line = get_line(fold.start_line as _, &document.text).to_string();
+            format!(
+                "{}:{}:{}: ({}, {} lines) {}",
+                filename,
+                range.start.line,
+                range.start.column,
+                fold_kind_label(fold.kind.as_ref()),
+                fold.end_line.saturating_sub(fold.start_line) + 1,
+                first_line.trim(),
+            )
+        })
+        .join("\n");
+    let command = format!(
+        "lsp-show-folding-ranges {} {}",
+        editor_quote(ctx.main_root(&meta)),
+        editor_quote(&contents),
+    );
+    ctx.exec(meta, command);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    #[test]
+    fn fold_range_to_kakoune_selects_whole_lines() {
+        let text = Rope::from_str("fn main() {\n    foo();\n    bar();\n}\n");
+        let fold = |start_line, end_line, end_character| FoldingRange {
+            start_line,
+            start_character: Some(10),
+            end_line,
+            end_character,
+            kind: None,
+            collapsed_text: None,
+        };
+        let range = |range: KakouneRange| format!("{}", ForwardKakouneRange(range));
+        assert_eq!(
+            range(fold_range_to_kakoune(
+                &fold(1, 2, None),
+                &text,
+                OffsetEncoding::Utf16
+            )),
+            "2.1,3.1000000"
+        );
+        assert_eq!(
+            range(fold_range_to_kakoune(
+                &fold(0, 3, Some(1)),
+                &text,
+                OffsetEncoding::Utf16
+            )),
+            "1.1,4.1000000"
+        );
+        // The last fold may end on a line without a newline.
+        let text = Rope::from_str("{\n}");
+        assert_eq!(
+            range(fold_range_to_kakoune(
+                &fold(0, 1, None),
+                &text,
+                OffsetEncoding::Utf16
+            )),
+            "1.1,2.1000000"
+        );
+    }
+}
@@ -2,25 +2,66 @@ use std::{borrow::Cow, collections::HashMap};
 
 use itertools::Itertools;
 use lsp_types::{
-    request::InlayHintRequest, InlayHint, InlayHintLabel, InlayHintParams, Position, Range,
+    request::{InlayHintRequest, InlayHintResolveRequest},
+    Hover, HoverContents, InlayHint, InlayHintLabel, InlayHintLabelPartTooltip, InlayHintParams,
+    InlayHintServerCapabilities, InlayHintTooltip, MarkedString, OneOf, Position, Range,
     TextDocumentIdentifier, TextEdit,
 };
 
 use crate::{
     capabilities::{attempt_server_capability, CAPABILITY_INLAY_HINTS},
     context::{Context, RequestParams},
+    language_features::{
+        code_action::execute_command_editor_command, goto::edit_at_range, hover::editor_hover,
+    },
     markup::escape_kakoune_markup,
     position::{
-        kakoune_range_to_lsp, lsp_position_to_kakoune, parse_kakoune_range, ranges_overlap,
+        get_file_contents, kakoune_range_to_lsp, lsp_position_to_kakoune, lsp_range_to_kakoune,
+        parse_kakoune_range, ranges_overlap,
     },
     text_edit::apply_text_edits,
-    types::{EditorMeta, ServerId},
-    util::{editor_quote, escape_tuple_element, file_path_to_uri},
+    types::{EditorHoverParams, EditorMeta, HoverType, KakounePosition, ServerId},
+    util::{editor_quote, escape_tuple_element, file_path_to_uri, uri_to_file_path},
 };
 
 #[derive(Debug, PartialEq, Clone, Default)]
 pub struct InlayHintsOptions {
     pub buf_line_count: u32,
+    // A window height of 0 requests hints for the whole buffer.
+    pub window_line: u32,
+    pub window_height: u32,
+}
+
+/// The lines of a buffer version for which inlay hints have been received.
+#[derive(Debug, Default)]
+pub struct InlayHintLines {
+    version: i32,
+    // Sorted, disjoint half-open line ranges.
+    ranges: Vec<(u32, u32)>,
+}
+
+impl InlayHintLines {
+    fn covers(&self, start: u32, end: u32) -> bool {
+        self.ranges
+            .iter()
+            .any(|&(covered_start, covered_end)| covered_start <= start && end <= covered_end)
+    }
+
+    fn add(&mut self, start: u32, end: u32) {
+        let (mut start, mut end) = (start, end);
+        self.ranges.retain(|&(covered_start, covered_end)| {
+            let touching = covered_start <= end && start <= covered_end;
+            if touching {
+                start = start.min(covered_start);
+                end = end.max(covered_end);
+            }
+            !touching
+        });
+        let index = self
+            .ranges
+            .partition_point(|&(covered_start, _)| covered_start < start);
+        self.ranges.insert(index, (start, end));
+    }
 }
 
 pub fn inlay_hints(meta: EditorMeta, params: InlayHintsOptions, ctx: &mut Context) {
@@ -32,6 +73,35 @@ pub fn inlay_hints(meta: EditorMeta, params: InlayHintsOptions, ctx: &mut Contex
         return;
     }
 
+    // Request the visible lines, plus one window height above and below, so that short
+    // scrolls are served from the hints we already have.
+    let (start, end, window_line) = if params.window_height == 0 {
+        (0, params.buf_line_count, None)
+    } else {
+        (
+            params.window_line.saturating_sub(params.window_height),
+            (params.window_line + 2 * params.window_height).min(params.buf_line_count),
+            Some(params.window_line),
+        )
+    };
+    if ctx
+        .inlay_hint_lines
+        .get(&meta.buffile)
+        .is_some_and(|lines| lines.version == meta.version && lines.covers(start, end))
+    {
+        let command = format!(
+            "set-option buffer lsp_inlay_hints_window_line {}",
+            window_line.map(|line| line as i64).unwrap_or(-1)
+        );
+        let command = format!(
+            "evaluate-commands -buffer {} -verbatim -- {}",
+            editor_quote(&meta.buffile),
+            &command
+        );
+        ctx.exec(meta, command);
+        return;
+    }
+
     let req_params = eligible_servers
         .into_iter()
         .map(|(server_id, _)| {
@@ -42,7 +112,7 @@ pub fn inlay_hints(meta: EditorMeta, params: InlayHintsOptions, ctx: &mut Contex
                     text_document: TextDocumentIdentifier {
                         uri: file_path_to_uri(&meta.buffile),
                     },
-                    range: Range::new(Position::new(0, 0), Position::new(params.buf_line_count, 0)),
+                    range: Range::new(Position::new(start, 0), Position::new(end, 0)),
                 }],
             )
         })
@@ -62,13 +132,16 @@ pub fn inlay_hints(meta: EditorMeta, params: InlayHintsOptions, ctx: &mut Contex
                     v
                 })
                 .collect();
-            inlay_hints_response(meta, results, ctx)
+            inlay_hints_response(meta, (start, end), window_line, results, ctx)
         },
     );
 }
 
+/// Merge the hints received for some lines into the hints of the buffer version, and show them.
 pub fn inlay_hints_response(
     meta: EditorMeta,
+    (start, end): (u32, u32),
+    window_line: Option<u32>,
     inlay_hints: Vec<(ServerId, InlayHint)>,
     ctx: &mut Context,
 ) {
@@ -77,7 +150,29 @@ pub fn inlay_hints_response(
         None => return,
     };
 
-    let ranges = inlay_hints
+    let all_hints = ctx.inlay_hints.entry(meta.buffile.clone()).or_default();
+    match ctx.inlay_hint_lines.get_mut(&meta.buffile) {
+        Some(lines) if lines.version == meta.version => {
+            all_hints.retain(|(_, hint)| !(start..end).contains(&hint.position.line));
+            lines.add(start, end);
+        }
+        _ => {
+            // Hints of other versions are outdated, or were invalidated by a refresh.
+            all_hints.clear();
+            ctx.inlay_hint_lines.insert(
+                meta.buffile.clone(),
+                InlayHintLines {
+                    version: meta.version,
+                    ranges: vec![(start, end)],
+                },
+            );
+        }
+    }
+    all_hints.extend(inlay_hints);
+    all_hints.sort_by_key(|(_, hint)| (hint.position.line, hint.position.character));
+    let all_hints = &ctx.inlay_hints[&meta.buffile];
+
+    let ranges = all_hints
         .iter()
         .map(
             |(
@@ -93,12 +188,7 @@ pub fn inlay_hints_response(
                 let server = ctx.server(*server_id);
                 let position =
                     lsp_position_to_kakoune(position, &document.text, server.offset_encoding);
-                let label = match label {
-                    InlayHintLabel::String(s) => Cow::Borrowed(s),
-                    InlayHintLabel::LabelParts(parts) => {
-                        Cow::Owned(parts.iter().map(|x| x.value.as_str()).collect())
-                    }
-                };
+                let label = label_text(label);
                 let padding_left = if padding_left.unwrap_or(false) {
                     " "
                 } else {
@@ -117,18 +207,29 @@ pub fn inlay_hints_response(
         )
         .join(" ");
 
-    ctx.inlay_hints.insert(meta.buffile.clone(), inlay_hints);
-
     let version = meta.version;
-    let command = format!("set-option buffer lsp_inlay_hints {version} {ranges}");
     let command = format!(
-        "evaluate-commands -buffer {} -verbatim -- {}",
+        "set-option buffer lsp_inlay_hints_window_line {}
+         set-option buffer lsp_inlay_hints {version} {ranges}",
+        window_line.map(|line| line as i64).unwrap_or(-1)
+    );
+    let command = format!(
+        "evaluate-commands -buffer {} {}",
         editor_quote(&meta.buffile),
-        &command
+        editor_quote(&command)
     );
     ctx.exec(meta, command)
 }
 
+fn label_text(label: &InlayHintLabel) -> Cow<'_, str> {
+    match label {
+        InlayHintLabel::String(s) => Cow::Borrowed(s),
+        InlayHintLabel::LabelParts(parts) => {
+            Cow::Owned(parts.iter().map(|x| x.value.as_str()).collect())
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum InlayHintApplyKind {
     /// Select the closest hint on the same line as the cursors
@@ -245,3 +346,200 @@ pub fn inlay_hint_apply(meta: EditorMeta, params: InlayHintApplyParams, ctx: &mu
         }
     }
 }
+
+#[derive(Debug)]
+pub struct InlayHintGotoParams {
+    pub selection_desc: String,
+}
+
+/// Jump to the location of a label part of the hint nearest the cursor, or run its command.
+/// If there are several label parts to choose from, they are offered in a menu.
+pub fn inlay_hint_goto(meta: EditorMeta, params: InlayHintGotoParams, ctx: &mut Context) {
+    let (_, cursor) = parse_kakoune_range(&params.selection_desc);
+    with_resolved_nearest_hint(meta, cursor, ctx, |meta, server_id, hint, ctx| {
+        let InlayHintLabel::LabelParts(parts) = &hint.label else {
+            ctx.show_error(meta, "inlay hint has no locations");
+            return;
+        };
+        let server = ctx.server(server_id);
+        let mut choices = vec![];
+        for part in parts {
+            if let Some(location) = &part.location {
+                let path = uri_to_file_path(&location.uri);
+                let path_str = path.to_str().unwrap();
+                let Some(contents) = get_file_contents(path_str, ctx) else {
+                    continue;
+                };
+                let range =
+                    lsp_range_to_kakoune(&location.range, &contents, server.offset_encoding);
+                let command = format!(
+                    "evaluate-commands -try-client %opt{{jumpclient}} -- {}",
+                    editor_quote(&edit_at_range(path_str, range, true)),
+                );
+                choices.push((part.value.trim(), command));
+            }
+            if let Some(command) = &part.command {
+                choices.push((
+                    command.title.as_str(),
+                    execute_command_editor_command(&server.name, command, false),
+                ));
+            }
+        }
+        match choices.len() {
+            0 => ctx.show_error(meta, "inlay hint has no locations"),
+            1 => {
+                let (_, command) = choices.pop().unwrap();
+                ctx.exec(meta, command);
+            }
+            _ => {
+                let choices = choices
+                    .into_iter()
+                    .map(|(label, command)| {
+                        format!("{} {}", editor_quote(label), editor_quote(&command))
+                    })
+                    .join(" ");
+                ctx.exec(meta, format!("lsp-menu {choices}"));
+            }
+        }
+    });
+}
+
+/// Show the tooltips of the hint nearest the cursor and of its label parts.
+pub fn inlay_hint_hover(meta: EditorMeta, params: EditorHoverParams, ctx: &mut Context) {
+    let hover_type = match params.hover_client {
+        Some(client) => HoverType::HoverBuffer { client },
+        None => HoverType::InfoBox,
+    };
+    let tabstop = params.tabstop;
+    let (range, cursor) = parse_kakoune_range(&params.selection_desc);
+    with_resolved_nearest_hint(meta, cursor, ctx, move |meta, server_id, hint, ctx| {
+        let hint_tooltip = hint.tooltip.map(|tooltip| match tooltip {
+            InlayHintTooltip::String(s) => HoverContents::Scalar(MarkedString::String(s)),
+            InlayHintTooltip::MarkupContent(markup) => HoverContents::Markup(markup),
+        });
+        let part_tooltips = match hint.label {
+            InlayHintLabel::String(_) => vec![],
+            InlayHintLabel::LabelParts(parts) => parts,
+        }
+        .into_iter()
+        .filter_map(|part| part.tooltip)
+        .map(|tooltip| match tooltip {
+            InlayHintLabelPartTooltip::String(s) => HoverContents::Scalar(MarkedString::String(s)),
+            InlayHintLabelPartTooltip::MarkupContent(markup) => HoverContents::Markup(markup),
+        });
+        let results: Vec<_> = hint_tooltip
+            .into_iter()
+            .chain(part_tooltips)
+            .map(|contents| {
+                (
+                    server_id,
+                    Some(Hover {
+                        contents,
+                        range: None,
+                    }),
+                )
+            })
+            .collect();
+        if results.is_empty() {
+            ctx.show_error(meta, "inlay hint has no tooltip");
+            return;
+        }
+        editor_hover(meta, hover_type, cursor, range, tabstop, results, ctx);
+    });
+}
+
+/// Find the hint nearest the cursor on its line, resolve it if needed and pass it to the callback.
+fn with_resolved_nearest_hint(
+    meta: EditorMeta,
+    cursor: KakounePosition,
+    ctx: &mut Context,
+    callback: impl FnOnce(EditorMeta, ServerId, InlayHint, &mut Context) + 'static,
+) {
+    let nearest = ctx
+        .documents
+        .get(&meta.buffile)
+        .zip(ctx.inlay_hints.get(&meta.buffile))
+        .and_then(|(document, hints)| {
+            hints
+                .iter()
+                .map(|(server_id, hint)| {
+                    let server = ctx.server(*server_id);
+                    let pos = lsp_position_to_kakoune(
+                        &hint.position,
+                        &document.text,
+                        server.offset_encoding,
+                    );
+                    (*server_id, hint, pos)
+                })
+                .filter(|(_, _, pos)| pos.line == cursor.line)
+                .min_by_key(|(_, _, pos)| pos.column.abs_diff(cursor.column))
+                .map(|(server_id, hint, _)| (server_id, hint.clone()))
+        });
+    let Some((server_id, hint)) = nearest else {
+        ctx.show_error(meta, "no inlay hint on this line");
+        return;
+    };
+    // Servers keep the data they need for resolving in the data field, and resolved hints
+    // are stored without it, so they are not resolved again.
+    if hint.data.is_none() || !supports_resolve(ctx, server_id) {
+        callback(meta, server_id, hint, ctx);
+        return;
+    }
+    let req_params = HashMap::from([(server_id, vec![hint.clone()])]);
+    ctx.call::<InlayHintResolveRequest, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx, meta, results| {
+            let Some((server_id, mut resolved)) = results.into_iter().next() else {
+                return;
+            };
+            resolved.data = None;
+            if let Some(hints) = ctx.inlay_hints.get_mut(&meta.buffile) {
+                // The hints may have been refreshed meanwhile.
+                if let Some(entry) = hints.iter_mut().find(|(id, h)| {
+                    *id == server_id
+                        && h.position == hint.position
+                        && label_text(&h.label) == label_text(&hint.label)
+                }) {
+                    entry.1 = resolved.clone();
+                }
+            }
+            callback(meta, server_id, resolved, ctx);
+        },
+    );
+}
+
+fn supports_resolve(ctx: &Context, server_id: ServerId) -> bool {
+    let provider = ctx
+        .server(server_id)
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.inlay_hint_provider.as_ref());
+    match provider {
+        Some(OneOf::Right(InlayHintServerCapabilities::Options(options))) => {
+            options.resolve_provider.unwrap_or(false)
+        }
+        Some(OneOf::Right(InlayHintServerCapabilities::RegistrationOptions(options))) => {
+            options.inlay_hint_options.resolve_provider.unwrap_or(false)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inlay_hint_lines_merge_touching_ranges() {
+        let mut lines = InlayHintLines::default();
+        lines.add(100, 200);
+        lines.add(0, 50);
+        assert!(lines.covers(120, 180));
+        assert!(!lines.covers(40, 120));
+        lines.add(50, 100);
+        assert_eq!(lines.ranges, vec![(0, 200)]);
+        lines.add(300, 400);
+        assert_eq!(lines.ranges, vec![(0, 200), (300, 400)]);
+    }
+}
@@ -0,0 +1,170 @@
+use crate::capabilities::attempt_server_capability;
+use crate::capabilities::CAPABILITY_LINKED_EDITING_RANGE;
+use crate::context::*;
+use crate::position::*;
+use crate::types::*;
+use crate::util::*;
+use indoc::formatdoc;
+use itertools::Itertools;
+use lsp_types::request::*;
+use lsp_types::*;
+
+// Without a word pattern from the server, only whitespace leaves the linked ranges.
+const DEFAULT_WORD_PATTERN: &str = r"\S+";
+
+#[derive(Debug)]
+pub struct EditorLinkedEditingRangeParams {
+    pub position: KakounePosition,
+    pub selection_desc: String,
+}
+
+pub fn text_document_linked_editing_range(
+    meta: EditorMeta,
+    params: EditorLinkedEditingRangeParams,
+    ctx: &mut Context,
+) {
+    let eligible_servers: Vec<_> = ctx
+        .servers(&meta)
+        .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_LINKED_EDITING_RANGE))
+        .collect();
+    if eligible_servers.is_empty() {
+        return;
+    }
+
+    let req_params = eligible_servers
+        .into_iter()
+        .map(|(server_id, server_settings)| {
+            (
+                server_id,
+                vec![LinkedEditingRangeParams {
+                    text_document_position_params: TextDocumentPositionParams {
+                        text_document: TextDocumentIdentifier {
+                            uri: file_path_to_uri(&meta.buffile),
+                        },
+                        position: get_lsp_position(
+                            server_settings,
+                            &meta.buffile,
+                            &params.position,
+                            ctx,
+                        )
+                        .unwrap(),
+                    },
+                    work_done_progress_params: Default::default(),
+                }],
+            )
+        })
+        .collect();
+    ctx.call::<LinkedEditingRange, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx: &mut Context, meta, results| {
+            if let Some((server_id, Some(ranges))) = results
+                .into_iter()
+                .find(|(_, v)| v.as_ref().is_some_and(|v| v.ranges.len() > 1))
+            {
+                editor_linked_editing_range(meta, params, server_id, ranges, ctx)
+            }
+        },
+    );
+}
+
+/// Add a selection on each linked range, at the same offsets as the main selection within its
+/// range.
+fn editor_linked_editing_range(
+    meta: EditorMeta,
+    params: EditorLinkedEditingRangeParams,
+    server_id: ServerId,
+    ranges: LinkedEditingRanges,
+    ctx: &mut Context,
+) {
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    let server = ctx.server(server_id);
+    let word_pattern = ranges.word_pattern;
+    let ranges: Vec<_> = ranges
+        .ranges
+        .iter()
+        .map(|range| {
+            (
+                lsp_position_to_kakoune(&range.start, &document.text, server.offset_encoding),
+                lsp_position_to_kakoune(&range.end, &document.text, server.offset_encoding),
+            )
+        })
+        // Linked ranges are usually names, which can't span lines.
+        .filter(|(start, end)| start.line == end.line)
+        .collect();
+
+    let (selection, cursor) = parse_kakoune_range(&params.selection_desc);
+    let anchor = if selection.start == cursor {
+        selection.end
+    } else {
+        selection.start
+    };
+    let contains = |(start, end): &(KakounePosition, KakounePosition),
+                    position: &KakounePosition| {
+        start <= position && position <= end
+    };
+    let Some(main_range) = ranges.iter().find(|range| contains(range, &cursor)) else {
+        return;
+    };
+    let cursor_offset = cursor.column - main_range.0.column;
+    let anchor_offset = if contains(main_range, &anchor) {
+        anchor.column - main_range.0.column
+    } else {
+        cursor_offset
+    };
+    // The editor counts characters to notice when the cursor would leave the range.
+    let line = get_line(cursor.line as usize - 1, &document.text);
+    let char_column = |column: u32| line.byte_to_char((column as usize - 1).min(line.len_bytes()));
+    let chars_before = char_column(cursor.column) - char_column(main_range.0.column);
+    let chars_after = char_column(main_range.1.column) - char_column(cursor.column);
+
+    let secondary_selections = ranges
+        .iter()
+        .filter(|range| *range != main_range)
+        .map(|(start, _)| {
+            format!(
+                "{}.{},{}.{}",
+                start.line,
+                start.column + anchor_offset,
+                start.line,
+                start.column + cursor_offset
+            )
+        })
+        .join(" ");
+    if secondary_selections.is_empty() {
+        return;
+    }
+
+    // Leave the linked ranges when typing a character that can't be part of a word. The hooks
+    // are installed first, so no selections are added if the editor rejects both patterns.
+    let start = |word_pattern: &str| {
+        format!(
+            "lsp-linked-editing-start {} {} {}",
+            editor_quote(&format!(r"(?!(?:{word_pattern})\z).*")),
+            chars_before,
+            chars_after,
+        )
+    };
+    let start = match word_pattern {
+        Some(word_pattern) => formatdoc!(
+            "try {} catch {}",
+            editor_quote(&start(&word_pattern)),
+            editor_quote(&formatdoc!(
+                "echo -debug kak-lsp: ignoring invalid linked editing word pattern: %val{{error}}
+                 {}",
+                start(DEFAULT_WORD_PATTERN)
+            )),
+        ),
+        None => start(DEFAULT_WORD_PATTERN),
+    };
+    let command = formatdoc!(
+        "{start}
+         select -timestamp {} {} {}",
+        meta.version,
+        params.selection_desc,
+        secondary_selections,
+    );
+    ctx.exec(meta, command);
+}
@@ -4,15 +4,22 @@ pub mod clangd;
 pub mod code_action;
 pub mod code_lens;
 pub mod completion;
+pub mod completion_ranking;
 pub mod cquery;
+pub mod document_color;
+pub mod document_link;
 pub mod document_symbol;
 pub mod eclipse_jdt_ls;
+pub mod file_operations;
+pub mod folding_range;
 pub mod formatting;
 pub mod goto;
 pub mod highlight;
 pub mod hover;
 pub mod inlay_hints;
 pub mod lean;
+pub mod linked_editing_range;
+pub mod on_type_formatting;
 pub mod range_formatting;
 pub mod rename;
 pub mod rust_analyzer;
@@ -20,3 +27,4 @@ pub mod selection_range;
 pub mod semantic_tokens;
 pub mod signature_help;
 pub mod texlab;
+pub mod type_hierarchy;
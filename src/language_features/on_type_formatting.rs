@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use crate::context::*;
+use crate::position::*;
+use crate::text_edit::apply_text_edits;
+use crate::types::*;
+use crate::util::editor_quote;
+use crate::util::file_path_to_uri;
+use itertools::Itertools;
+use lsp_types::request::*;
+use lsp_types::*;
+
+/// Trigger characters of a server, if on-type formatting is enabled for it in the config.
+fn trigger_characters(meta: &EditorMeta, server: &ServerSettings, ctx: &Context) -> Vec<String> {
+    let enabled = ctx
+        .server_config(meta, &server.name)
+        .and_then(|cfg| cfg.on_type_formatting)
+        .unwrap_or(false);
+    if !enabled {
+        return vec![];
+    }
+    let Some(options) = server
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.document_on_type_formatting_provider.as_ref())
+    else {
+        return vec![];
+    };
+    let mut characters = vec![options.first_trigger_character.clone()];
+    characters.extend(options.more_trigger_character.iter().flatten().cloned());
+    characters
+}
+
+/// Install an InsertChar hook in the buffer that fires on the trigger characters of its servers.
+pub fn install_trigger_hook(meta: &EditorMeta, ctx: &Context) {
+    let characters: Vec<_> = ctx
+        .servers(meta)
+        .flat_map(|(_, server)| trigger_characters(meta, server, ctx))
+        .unique()
+        .collect();
+    if characters.is_empty() {
+        return;
+    }
+    let regex = characters
+        .iter()
+        .map(|ch| match ch.as_str() {
+            "\n" => "\\n".to_string(),
+            ch => format!("\\Q{}\\E", ch),
+        })
+        .join("|");
+    let command = format!(
+        "remove-hooks buffer lsp-on-type-formatting
+         hook -group lsp-on-type-formatting buffer InsertChar {} %{{ try lsp-on-type-formatting-request }}",
+        editor_quote(&regex)
+    );
+    let command = format!(
+        "evaluate-commands -buffer {} {}",
+        editor_quote(&meta.buffile),
+        editor_quote(&command)
+    );
+    ctx.exec(EditorMeta::default(), command);
+}
+
+pub fn text_document_on_type_formatting(
+    meta: EditorMeta,
+    params: OnTypeFormattingParams,
+    ctx: &mut Context,
+) {
+    // Only ask one server, to avoid conflicting edits.
+    let Some(server_id) = ctx
+        .servers(&meta)
+        .find(|(_, server)| trigger_characters(&meta, server, ctx).contains(&params.ch))
+        .map(|(server_id, _)| server_id)
+    else {
+        return;
+    };
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    let server = ctx.server(server_id);
+    let mut req_params = HashMap::new();
+    req_params.insert(
+        server_id,
+        vec![DocumentOnTypeFormattingParams {
+            text_document_position: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier {
+                    uri: file_path_to_uri(&meta.buffile),
+                },
+                position: kakoune_position_to_lsp(
+                    &params.position,
+                    &document.text,
+                    server.offset_encoding,
+                ),
+            },
+            ch: params.ch,
+            options: params.formatting_options,
+        }],
+    );
+    ctx.call::<OnTypeFormatting, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx, meta, mut results| {
+            let Some(text_edits) = results.first_mut().and_then(|(_, v)| v.take()) else {
+                return;
+            };
+            // The user has kept typing, these edits would apply to stale positions.
+            if !ctx
+                .documents
+                .get(&meta.buffile)
+                .is_some_and(|document| document.version == meta.version)
+            {
+                return;
+            }
+            let uri = file_path_to_uri(&meta.buffile);
+            apply_text_edits(server_id, meta, uri, text_edits, ctx);
+        },
+    );
+}
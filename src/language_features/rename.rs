@@ -1,16 +1,116 @@
+use crate::capabilities::{attempt_server_capability, CAPABILITY_RENAME};
 use crate::context::*;
 use crate::position::*;
 use crate::types::*;
+use crate::util::editor_quote;
 use crate::util::file_path_to_uri;
 
+use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
+use ropey::Rope;
 
-use super::super::workspace;
+use super::code_action::apply_workspace_edit_editor_command;
+use crate::edit_preview;
+
+/// Ask the server whether the symbol at the cursor can be renamed, and prompt for the new name.
+pub fn text_document_prepare_rename(meta: EditorMeta, params: PositionParams, ctx: &mut Context) {
+    let eligible_servers: Vec<_> = ctx
+        .servers(&meta)
+        .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_RENAME))
+        .filter(|(_, server)| {
+            matches!(
+                server.capabilities.as_ref().unwrap().rename_provider,
+                Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    ..
+                }))
+            )
+        })
+        .collect();
+    if eligible_servers.is_empty() {
+        ctx.exec(meta, "lsp-rename-prompt-word");
+        return;
+    }
+
+    let req_params = eligible_servers
+        .into_iter()
+        .map(|(server_id, server_settings)| {
+            (
+                server_id,
+                vec![TextDocumentPositionParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: file_path_to_uri(&meta.buffile),
+                    },
+                    position: get_lsp_position(
+                        server_settings,
+                        &meta.buffile,
+                        &params.position,
+                        ctx,
+                    )
+                    .unwrap(),
+                }],
+            )
+        })
+        .collect();
+    ctx.call::<PrepareRenameRequest, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx: &mut Context, meta, results| {
+            let Some((server_id, response)) = results
+                .into_iter()
+                .find_map(|(server_id, response)| Some((server_id, response?)))
+            else {
+                ctx.show_error(
+                    meta,
+                    "lsp-rename-prompt: cannot rename the symbol at the cursor",
+                );
+                return;
+            };
+            let placeholder = match response {
+                PrepareRenameResponse::RangeWithPlaceholder { placeholder, .. } => placeholder,
+                PrepareRenameResponse::Range(range) => {
+                    let Some(document) = ctx.documents.get(&meta.buffile) else {
+                        return;
+                    };
+                    let server = ctx.server(server_id);
+                    match lsp_range_text(&range, &document.text, server.offset_encoding) {
+                        Some(text) => text,
+                        None => {
+                            ctx.show_error(meta, "lsp-rename-prompt: invalid range from server");
+                            return;
+                        }
+                    }
+                }
+                PrepareRenameResponse::DefaultBehavior { .. } => {
+                    ctx.exec(meta, "lsp-rename-prompt-word");
+                    return;
+                }
+            };
+            let command = format!(
+                "lsp-rename-prompt-with-placeholder {}",
+                editor_quote(&placeholder)
+            );
+            ctx.exec(meta, command);
+        },
+    );
+}
+
+fn lsp_range_text(range: &Range, text: &Rope, offset_encoding: OffsetEncoding) -> Option<String> {
+    let char_index = |position: &Position| {
+        let line = text.get_line(position.line as _)?;
+        let offset = lsp_character_to_byte_offset(line, position.character as _, offset_encoding)?;
+        Some(text.byte_to_char(text.line_to_byte(position.line as _) + offset))
+    };
+    let start = char_index(&range.start)?;
+    let end = char_index(&range.end)?;
+    (start <= end).then(|| text.slice(start..end).to_string())
+}
 
 pub fn text_document_rename(meta: EditorMeta, params: TextDocumentRenameParams, ctx: &mut Context) {
     let req_params = ctx
         .servers(&meta)
+        .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_RENAME))
         .map(|(server_id, server_settings)| {
             (
                 server_id,
@@ -37,22 +137,204 @@ pub fn text_document_rename(meta: EditorMeta, params: TextDocumentRenameParams,
         meta,
         RequestParams::Each(req_params),
         move |ctx: &mut Context, meta, results| {
-            let result = match results.into_iter().find(|(_, v)| v.is_some()) {
-                Some(result) => result,
-                None => (meta.servers[0], None),
-            };
-
-            editor_rename(meta, result, ctx)
+            if let Some((server_id, Some(edit))) = results.into_iter().find(|(_, v)| v.is_some()) {
+                editor_rename(meta, server_id, edit, ctx)
+            }
         },
     );
 }
 
-// TODO handle version, so change is not applied if buffer is modified (and need to show a warning)
-fn editor_rename(meta: EditorMeta, result: (ServerId, Option<WorkspaceEdit>), ctx: &mut Context) {
-    let (server_id, result) = result;
-    if result.is_none() {
+fn editor_rename(meta: EditorMeta, server_id: ServerId, edit: WorkspaceEdit, ctx: &mut Context) {
+    let labels = confirmation_labels(&edit);
+    if labels.is_empty() {
+        edit_preview::apply_or_preview_edit(server_id, meta, None, None, edit, None, ctx);
         return;
     }
-    let result = result.unwrap();
-    workspace::apply_edit(server_id, meta, None, result, ctx);
+    let skipped = without_changes_needing_confirmation(edit.clone());
+    let command = format!(
+        "lsp-menu {} {} {} {}",
+        editor_quote(&format!(
+            "Apply all changes, including: {}",
+            labels.join(", ")
+        )),
+        editor_quote(&apply_workspace_edit_editor_command(&edit, false)),
+        editor_quote("Skip changes that need confirmation"),
+        editor_quote(&apply_workspace_edit_editor_command(&skipped, false)),
+    );
+    ctx.exec(meta, command);
+}
+
+fn needs_confirmation(edit: &WorkspaceEdit, annotation_id: Option<&String>) -> bool {
+    let Some(annotation_id) = annotation_id else {
+        return false;
+    };
+    edit.change_annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(annotation_id))
+        .is_some_and(|annotation| annotation.needs_confirmation == Some(true))
+}
+
+fn resource_op_annotation_id(op: &ResourceOp) -> Option<&String> {
+    match op {
+        ResourceOp::Create(op) => op.annotation_id.as_ref(),
+        ResourceOp::Rename(op) => op.annotation_id.as_ref(),
+        ResourceOp::Delete(op) => op.options.as_ref()?.annotation_id.as_ref(),
+    }
+}
+
+/// Labels of the change annotations that are used by the edit and need confirmation.
+fn confirmation_labels(edit: &WorkspaceEdit) -> Vec<String> {
+    let text_edit_annotations = |text_document_edit: &TextDocumentEdit| {
+        text_document_edit
+            .edits
+            .iter()
+            .filter_map(|edit| match edit {
+                OneOf::Left(_) => None,
+                OneOf::Right(edit) => Some(edit.annotation_id.clone()),
+            })
+            .collect::<Vec<_>>()
+    };
+    let annotation_ids: Vec<_> = match &edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => {
+            edits.iter().flat_map(text_edit_annotations).collect()
+        }
+        Some(DocumentChanges::Operations(ops)) => ops
+            .iter()
+            .flat_map(|op| match op {
+                DocumentChangeOperation::Edit(edit) => text_edit_annotations(edit),
+                DocumentChangeOperation::Op(op) => {
+                    resource_op_annotation_id(op).cloned().into_iter().collect()
+                }
+            })
+            .collect(),
+        None => vec![],
+    };
+    annotation_ids
+        .iter()
+        .filter(|id| needs_confirmation(edit, Some(id)))
+        .unique()
+        .map(|id| edit.change_annotations.as_ref().unwrap()[id].label.clone())
+        .collect()
+}
+
+fn without_changes_needing_confirmation(mut edit: WorkspaceEdit) -> WorkspaceEdit {
+    let filter_text_edits = |edit: &WorkspaceEdit, text_document_edit: &mut TextDocumentEdit| {
+        text_document_edit
+            .edits
+            .retain(|text_edit| match text_edit {
+                OneOf::Left(_) => true,
+                OneOf::Right(text_edit) => {
+                    !needs_confirmation(edit, Some(&text_edit.annotation_id))
+                }
+            });
+    };
+    match edit.document_changes.take() {
+        Some(DocumentChanges::Edits(mut edits)) => {
+            for text_document_edit in &mut edits {
+                filter_text_edits(&edit, text_document_edit);
+            }
+            edit.document_changes = Some(DocumentChanges::Edits(edits));
+        }
+        Some(DocumentChanges::Operations(mut ops)) => {
+            ops.retain(|op| match op {
+                DocumentChangeOperation::Edit(_) => true,
+                DocumentChangeOperation::Op(op) => {
+                    !needs_confirmation(&edit, resource_op_annotation_id(op))
+                }
+            });
+            for op in &mut ops {
+                if let DocumentChangeOperation::Edit(text_document_edit) = op {
+                    filter_text_edits(&edit, text_document_edit);
+                }
+            }
+            edit.document_changes = Some(DocumentChanges::Operations(ops));
+        }
+        None => (),
+    }
+    edit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn without_changes_needing_confirmation_keeps_plain_changes() {
+        let uri = |path: &str| file_path_to_uri(path);
+        let text_edit = |new_text: &str| TextEdit {
+            range: Range::default(),
+            new_text: new_text.to_string(),
+        };
+        let annotated = |new_text: &str, annotation_id: &str| {
+            OneOf::Right(AnnotatedTextEdit {
+                text_edit: text_edit(new_text),
+                annotation_id: annotation_id.to_string(),
+            })
+        };
+        let annotation = |label: &str, needs_confirmation| ChangeAnnotation {
+            label: label.to_string(),
+            needs_confirmation: Some(needs_confirmation),
+            description: None,
+        };
+        let edit = WorkspaceEdit {
+            changes: None,
+            document_changes: Some(DocumentChanges::Operations(vec![
+                DocumentChangeOperation::Edit(TextDocumentEdit {
+                    text_document: OptionalVersionedTextDocumentIdentifier {
+                        uri: uri("/a.rs"),
+                        version: None,
+                    },
+                    edits: vec![
+                        OneOf::Left(text_edit("plain")),
+                        annotated("comment", "comments"),
+                        annotated("code", "code"),
+                    ],
+                }),
+                DocumentChangeOperation::Op(ResourceOp::Rename(RenameFile {
+                    old_uri: uri("/b.rs"),
+                    new_uri: uri("/c.rs"),
+                    options: None,
+                    annotation_id: Some("files".to_string()),
+                })),
+                DocumentChangeOperation::Op(ResourceOp::Create(CreateFile {
+                    uri: uri("/d.rs"),
+                    options: None,
+                    annotation_id: None,
+                })),
+            ])),
+            change_annotations: Some(HashMap::from([
+                ("comments".to_string(), annotation("In comments", true)),
+                ("code".to_string(), annotation("In code", false)),
+                ("files".to_string(), annotation("Rename files", true)),
+            ])),
+        };
+
+        assert!(needs_confirmation(&edit, Some(&"comments".to_string())));
+        assert!(!needs_confirmation(&edit, Some(&"code".to_string())));
+        assert!(!needs_confirmation(&edit, Some(&"unknown".to_string())));
+        assert!(!needs_confirmation(&edit, None));
+        assert_eq!(confirmation_labels(&edit), ["In comments", "Rename files"]);
+        let skipped = without_changes_needing_confirmation(edit);
+        let Some(DocumentChanges::Operations(ops)) = skipped.document_changes else {
+            panic!("expected operations");
+        };
+        assert_eq!(ops.len(), 2);
+        let DocumentChangeOperation::Edit(text_document_edit) = &ops[0] else {
+            panic!("expected a text document edit");
+        };
+        let new_texts: Vec<_> = text_document_edit
+            .edits
+            .iter()
+            .map(|edit| match edit {
+                OneOf::Left(edit) => edit.new_text.as_str(),
+                OneOf::Right(edit) => edit.text_edit.new_text.as_str(),
+            })
+            .collect();
+        assert_eq!(new_texts, ["plain", "code"]);
+        assert!(matches!(
+            ops[1],
+            DocumentChangeOperation::Op(ResourceOp::Create(_))
+        ));
+    }
 }
@@ -1,9 +1,10 @@
 use crate::context::{Context, RequestParams};
+use crate::edit_journal::EditJournal;
 use crate::position::{get_lsp_position, lsp_position_to_kakoune};
 use crate::text_edit::apply_text_edits_try_deferred;
 use crate::types::{EditorMeta, KakounePosition, PositionParams};
 use crate::util::{editor_escape, editor_quote, file_path_to_uri, uri_to_file_path};
-use crate::{workspace, ResponseFifo};
+use crate::{snippet, workspace, ResponseFifo};
 use itertools::Itertools;
 use lsp_types::request::Request;
 use lsp_types::*;
@@ -77,7 +78,10 @@ pub fn apply_source_change(
         for op in document_changes {
             match op {
                 SnippetDocumentChangeOperation::Op(resource_op) => {
-                    if let Err(e) = workspace::apply_document_resource_op(resource_op) {
+                    if let Err(e) = workspace::apply_document_resource_op(
+                        resource_op,
+                        &mut EditJournal::default(),
+                    ) {
                         error!(ctx.to_editor(), "failed to apply document change: {}", e);
                     }
                 }
@@ -85,23 +89,49 @@ pub fn apply_source_change(
                     text_document: VersionedTextDocumentIdentifier { uri, .. },
                     edits,
                 }) => {
+                    let variables = snippet::variables(&meta, ctx);
                     let edits: Vec<TextEdit> = edits
                         .into_iter()
                         .map(
                             |SnippetTextEdit {
                                  range,
                                  new_text,
-                                 insert_text_format: _, // TODO
-                             }| TextEdit { range, new_text },
+                                 insert_text_format,
+                             }| {
+                                // Tabstops are dropped, the cursor position is sent separately.
+                                let new_text =
+                                    if insert_text_format == Some(InsertTextFormat::SNIPPET) {
+                                        snippet::expand(&new_text, &variables).text()
+                                    } else {
+                                        new_text
+                                    };
+                                TextEdit { range, new_text }
+                            },
                         )
                         .collect();
-                    apply_text_edits_try_deferred(&mut command, server_id, &meta, uri, edits, ctx);
+                    let _ = apply_text_edits_try_deferred(
+                        &mut command,
+                        None,
+                        server_id,
+                        &meta,
+                        uri,
+                        edits,
+                        ctx,
+                    );
                 }
             }
         }
     } else if let Some(changes) = changes {
         for (uri, change) in changes {
-            apply_text_edits_try_deferred(&mut command, server_id, &meta, uri, change, ctx);
+            let _ = apply_text_edits_try_deferred(
+                &mut command,
+                None,
+                server_id,
+                &meta,
+                uri,
+                change,
+                ctx,
+            );
         }
     }
     let Some(TextDocumentPositionParams {
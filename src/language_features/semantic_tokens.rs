@@ -1,94 +1,335 @@
+use std::collections::{HashMap, HashSet};
+
 use crate::capabilities::{attempt_server_capability, CAPABILITY_SEMANTIC_TOKENS};
 use crate::context::{Context, RequestParams};
-use crate::position::lsp_range_to_kakoune;
+use crate::position::{
+    char_index_to_lsp_position, lsp_character_to_byte_offset, lsp_range_to_kakoune,
+};
 use crate::semantic_tokens_config;
-use crate::types::{EditorMeta, ForwardKakouneRange, ServerId};
+use crate::types::{EditorMeta, ForwardKakouneRange, KakouneRange, OffsetEncoding, ServerId};
 use crate::util::editor_quote;
 use crate::util::file_path_to_uri;
-use lsp_types::request::SemanticTokensFullRequest;
+use itertools::Itertools;
+use lsp_types::request::{
+    SemanticTokensFullDeltaRequest, SemanticTokensFullRequest, SemanticTokensRangeRequest,
+};
 use lsp_types::{
-    Position, Range, SemanticToken, SemanticTokenModifier, SemanticTokensOptions,
-    SemanticTokensParams, SemanticTokensRegistrationOptions, SemanticTokensResult,
+    Position, Range, SemanticToken, SemanticTokenModifier, SemanticTokensDeltaParams,
+    SemanticTokensEdit, SemanticTokensFullDeltaResult, SemanticTokensFullOptions,
+    SemanticTokensLegend, SemanticTokensOptions, SemanticTokensParams, SemanticTokensRangeParams,
+    SemanticTokensRangeResult, SemanticTokensRegistrationOptions, SemanticTokensResult,
     SemanticTokensServerCapabilities::*, TextDocumentIdentifier,
 };
+use ropey::Rope;
+
+#[derive(Clone, Debug)]
+pub struct EditorSemanticTokensParams {
+    // Buffers with more lines only get tokens for the visible lines, if the server supports it.
+    pub max_full_lines: usize,
+    pub window_line: u32,
+    pub window_height: u32,
+}
+
+/// The last semantic tokens of a buffer.
+pub struct SemanticTokensState {
+    server_id: ServerId,
+    // The result ID and tokens of the last full response, to request deltas.
+    result_id: Option<String>,
+    data: Vec<SemanticToken>,
+    // The highlighter ranges last sent to the editor, and the buffer version they apply to.
+    version: i32,
+    ranges: Vec<String>,
+}
+
+fn semantic_tokens_options(ctx: &Context, server_id: ServerId) -> Option<&SemanticTokensOptions> {
+    match ctx
+        .server(server_id)
+        .capabilities
+        .as_ref()?
+        .semantic_tokens_provider
+        .as_ref()?
+    {
+        SemanticTokensOptions(options)
+        | SemanticTokensRegistrationOptions(SemanticTokensRegistrationOptions {
+            semantic_tokens_options: options,
+            ..
+        }) => Some(options),
+    }
+}
 
-pub fn tokens_request(meta: EditorMeta, ctx: &mut Context) {
-    let eligible_servers: Vec<_> = ctx
+pub fn tokens_request(meta: EditorMeta, params: EditorSemanticTokensParams, ctx: &mut Context) {
+    // Only one server's tokens can be shown.
+    let Some((server_id, _)) = ctx
         .servers(&meta)
-        .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_SEMANTIC_TOKENS))
-        .collect();
-    if eligible_servers.is_empty() {
+        .find(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_SEMANTIC_TOKENS))
+    else {
+        return;
+    };
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return;
+    };
+    let options = semantic_tokens_options(ctx, server_id).unwrap();
+    let text_document = TextDocumentIdentifier {
+        uri: file_path_to_uri(&meta.buffile),
+    };
+
+    let line_count = document.text.len_lines();
+    if line_count > params.max_full_lines && options.range == Some(true) {
+        // Deltas can't be computed against tokens of a range.
+        if let Some(state) = ctx.semantic_tokens.get_mut(&meta.buffile) {
+            state.result_id = None;
+            state.data.clear();
+        }
+        let start = params.window_line.min(line_count as u32);
+        let end = (start + params.window_height + 1).min(line_count as u32);
+        let mut req_params = HashMap::new();
+        req_params.insert(
+            server_id,
+            vec![SemanticTokensRangeParams {
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                text_document,
+                range: Range {
+                    start: Position::new(start, 0),
+                    end: Position::new(end, 0),
+                },
+            }],
+        );
+        ctx.call::<SemanticTokensRangeRequest, _>(
+            meta,
+            RequestParams::Each(req_params),
+            move |ctx, meta, results| {
+                let Some((server_id, Some(result))) = results.into_iter().next() else {
+                    return;
+                };
+                let data = match result {
+                    SemanticTokensRangeResult::Tokens(tokens) => tokens.data,
+                    SemanticTokensRangeResult::Partial(partial) => partial.data,
+                };
+                tokens_response(meta, server_id, data, Some(params.window_line), ctx);
+            },
+        );
         return;
     }
 
-    let (first_server, _) = *eligible_servers.first().unwrap();
-    let first_server = first_server.to_owned();
+    let previous_result_id = ctx
+        .semantic_tokens
+        .get(&meta.buffile)
+        .filter(|state| state.server_id == server_id)
+        .and_then(|state| state.result_id.clone());
+    let supports_delta = matches!(
+        options.full,
+        Some(SemanticTokensFullOptions::Delta { delta: Some(true) })
+    );
+    if let (true, Some(previous_result_id)) = (supports_delta, previous_result_id) {
+        let mut req_params = HashMap::new();
+        req_params.insert(
+            server_id,
+            vec![SemanticTokensDeltaParams {
+                work_done_progress_params: Default::default(),
+                partial_result_params: Default::default(),
+                text_document,
+                previous_result_id: previous_result_id.clone(),
+            }],
+        );
+        ctx.call::<SemanticTokensFullDeltaRequest, _>(
+            meta,
+            RequestParams::Each(req_params),
+            move |ctx, meta, results| {
+                let Some((server_id, Some(result))) = results.into_iter().next() else {
+                    return;
+                };
+                let (result_id, edits) = match result {
+                    SemanticTokensFullDeltaResult::Tokens(tokens) => {
+                        full_tokens_response(meta, server_id, tokens.result_id, tokens.data, ctx);
+                        return;
+                    }
+                    SemanticTokensFullDeltaResult::TokensDelta(delta) => {
+                        (delta.result_id, delta.edits)
+                    }
+                    SemanticTokensFullDeltaResult::PartialTokensDelta { edits } => (None, edits),
+                };
+                let Some(state) = ctx.semantic_tokens.get(&meta.buffile) else {
+                    return;
+                };
+                // Another response may have replaced the tokens that the delta applies to.
+                if state.result_id.as_ref() != Some(&previous_result_id) {
+                    full_tokens_request(meta, server_id, ctx);
+                    return;
+                }
+                match apply_semantic_tokens_edits(&state.data, edits) {
+                    Some(data) => full_tokens_response(meta, server_id, result_id, data, ctx),
+                    None => {
+                        warn!(
+                            ctx.to_editor(),
+                            "semantic tokens delta is not aligned to tokens, requesting all tokens"
+                        );
+                        full_tokens_request(meta, server_id, ctx);
+                    }
+                }
+            },
+        );
+        return;
+    }
 
-    let req_params = eligible_servers
-        .into_iter()
-        .map(|(server_id, _)| {
-            (
-                server_id,
-                vec![SemanticTokensParams {
-                    partial_result_params: Default::default(),
-                    text_document: TextDocumentIdentifier {
-                        uri: file_path_to_uri(&meta.buffile),
-                    },
-                    work_done_progress_params: Default::default(),
-                }],
-            )
-        })
-        .collect();
+    full_tokens_request(meta, server_id, ctx);
+}
+
+fn full_tokens_request(meta: EditorMeta, server_id: ServerId, ctx: &mut Context) {
+    let text_document = TextDocumentIdentifier {
+        uri: file_path_to_uri(&meta.buffile),
+    };
+    let mut req_params = HashMap::new();
+    req_params.insert(
+        server_id,
+        vec![SemanticTokensParams {
+            partial_result_params: Default::default(),
+            text_document,
+            work_done_progress_params: Default::default(),
+        }],
+    );
     ctx.call::<SemanticTokensFullRequest, _>(
         meta,
         RequestParams::Each(req_params),
         move |ctx, meta, results| {
-            let (server_id, response) = match results.into_iter().find(|(_, v)| v.is_some()) {
-                Some(result) => result,
-                None => (first_server, None),
+            let Some((server_id, Some(result))) = results.into_iter().next() else {
+                return;
             };
-
-            if let Some(response) = response {
-                tokens_response(meta, (server_id, response), ctx);
-            }
+            let (result_id, data) = match result {
+                SemanticTokensResult::Tokens(tokens) => (tokens.result_id, tokens.data),
+                SemanticTokensResult::Partial(partial) => (None, partial.data),
+            };
+            full_tokens_response(meta, server_id, result_id, data, ctx);
         },
     );
 }
 
-pub fn tokens_response(
+/// Apply delta edits to the previous token array. The edits refer to indices in the original
+/// array, so apply them back to front. Returns None if an edit doesn't start and end at token
+/// boundaries.
+fn apply_semantic_tokens_edits(
+    data: &[SemanticToken],
+    mut edits: Vec<SemanticTokensEdit>,
+) -> Option<Vec<SemanticToken>> {
+    // Each token is encoded as 5 integers.
+    const TOKEN_SIZE: u32 = 5;
+    let mut data = data.to_vec();
+    edits.sort_by_key(|edit| std::cmp::Reverse(edit.start));
+    for edit in edits {
+        if edit.start % TOKEN_SIZE != 0 || edit.delete_count % TOKEN_SIZE != 0 {
+            return None;
+        }
+        let start = ((edit.start / TOKEN_SIZE) as usize).min(data.len());
+        let end = (start + (edit.delete_count / TOKEN_SIZE) as usize).min(data.len());
+        data.splice(start..end, edit.data.unwrap_or_default());
+    }
+    Some(data)
+}
+
+fn full_tokens_response(
     meta: EditorMeta,
-    response: (ServerId, SemanticTokensResult),
+    server_id: ServerId,
+    result_id: Option<String>,
+    data: Vec<SemanticToken>,
     ctx: &mut Context,
 ) {
-    let (server_id, tokens) = response;
-    let server = ctx.server(server_id);
-    let legend = match server
-        .capabilities
-        .as_ref()
-        .unwrap()
-        .semantic_tokens_provider
-    {
-        Some(SemanticTokensOptions(SemanticTokensOptions { ref legend, .. }))
-        | Some(SemanticTokensRegistrationOptions(SemanticTokensRegistrationOptions {
-            semantic_tokens_options: SemanticTokensOptions { ref legend, .. },
-            ..
-        })) => legend,
-        None => return,
+    tokens_response(meta.clone(), server_id, data.clone(), None, ctx);
+    if let Some(state) = ctx.semantic_tokens.get_mut(&meta.buffile) {
+        state.result_id = result_id;
+        state.data = data;
+    }
+}
+
+/// Send the highlighter ranges for the tokens to the editor. Only changes are sent if the
+/// buffer has not been modified since the last update.
+fn tokens_response(
+    meta: EditorMeta,
+    server_id: ServerId,
+    data: Vec<SemanticToken>,
+    window_line: Option<u32>,
+    ctx: &mut Context,
+) {
+    let Some(legend) = semantic_tokens_options(ctx, server_id).map(|options| &options.legend)
+    else {
+        return;
     };
-    let document = match ctx.documents.get(&meta.buffile) {
-        Some(document) => document,
-        None => return,
+    let ranges = token_ranges(&meta, server_id, legend, &data, ctx);
+
+    let version = meta.version;
+    let previous = ctx
+        .semantic_tokens
+        .get(&meta.buffile)
+        .filter(|state| state.version == version)
+        .map(|state| state.ranges.iter().collect::<HashSet<_>>());
+    let mut command = match previous {
+        Some(previous)
+            if previous.len() == ranges.len()
+                && ranges.iter().all(|range| previous.contains(range)) =>
+        {
+            String::new()
+        }
+        Some(previous) if previous.iter().all(|range| ranges.contains(range)) => {
+            let added = ranges
+                .iter()
+                .filter(|range| !previous.contains(range))
+                .join(" ");
+            format!("set-option -add buffer lsp_semantic_tokens_ranges {added}\n")
+        }
+        _ => {
+            let ranges = ranges.join(" ");
+            format!("set-option buffer lsp_semantic_tokens_ranges {version} {ranges}\n")
+        }
     };
-    let tokens = match tokens {
-        SemanticTokensResult::Tokens(tokens) => tokens.data,
-        SemanticTokensResult::Partial(partial) => partial.data,
+    // Large buffers are highlighted only in the visible lines, so the editor must request
+    // tokens again when the window scrolls.
+    command += &format!(
+        "set-option buffer lsp_semantic_tokens_window_line {}",
+        window_line.map(|line| line as i64).unwrap_or(-1)
+    );
+    let command = format!(
+        "evaluate-commands -buffer {} {}",
+        editor_quote(&meta.buffile),
+        editor_quote(&command)
+    );
+    ctx.exec(meta.clone(), command);
+
+    let state = ctx
+        .semantic_tokens
+        .entry(meta.buffile)
+        .or_insert_with(|| SemanticTokensState {
+            server_id,
+            result_id: None,
+            data: vec![],
+            version,
+            ranges: vec![],
+        });
+    if state.server_id != server_id {
+        state.server_id = server_id;
+        state.result_id = None;
+        state.data = vec![];
+    }
+    state.version = version;
+    state.ranges = ranges;
+}
+
+/// Convert the tokens to range-specs with the faces configured in lsp_semantic_tokens.
+fn token_ranges(
+    meta: &EditorMeta,
+    server_id: ServerId,
+    legend: &SemanticTokensLegend,
+    data: &[SemanticToken],
+    ctx: &Context,
+) -> Vec<String> {
+    let server = ctx.server(server_id);
+    let Some(document) = ctx.documents.get(&meta.buffile) else {
+        return vec![];
     };
     let mut line = 0;
     let mut start = 0;
-    let ranges = tokens
-        .into_iter()
+    let mut ranges: Vec<(KakouneRange, usize, &str)> = data
+        .iter()
         .filter_map(
-            |SemanticToken {
+            |&SemanticToken {
                  delta_line,
                  delta_start,
                  length,
@@ -101,23 +342,18 @@ pub fn tokens_response(
                 } else {
                     start += delta_start;
                 }
-                let range = Range {
-                    start: Position::new(line, start),
-                    end: Position::new(line, start + length),
-                };
-                let range = lsp_range_to_kakoune(&range, &document.text, server.offset_encoding);
                 // See the spec for information on the integer encoding:
                 // https://microsoft.github.io/language-server-protocol/specifications/specification-current/#textDocument_semanticTokens
-                let token_name = legend.token_types[token_type as usize].as_str();
+                let token_name = legend.token_types.get(token_type as usize)?.as_str();
                 let token_modifiers: Vec<&SemanticTokenModifier> = (0..32)
                     // Find bits in the mask that equal `1`
                     .filter(|bit| ((token_modifiers_bitset >> bit) & 1u32) == 1u32)
                     // Map bits to modifiers
-                    .map(|bit| &legend.token_modifiers[bit as usize])
+                    .filter_map(|bit| legend.token_modifiers.get(bit as usize))
                     .collect();
 
                 let candidates =
-                    semantic_tokens_config(&ctx.config, &meta)
+                    semantic_tokens_config(&ctx.config, meta)
                         .iter()
                         .filter(|token_config| {
                             token_name == token_config.token &&
@@ -136,22 +372,126 @@ pub fn tokens_response(
                         .iter()
                         .filter(|modifier| token_config.modifiers.contains(modifier))
                         .count()
-                });
+                })?;
 
-                best.map(|token_config| {
-                    format!("{}|{}", ForwardKakouneRange(range), token_config.face)
-                })
+                let token_ranges =
+                    token_line_ranges(line, start, length, &document.text, server.offset_encoding);
+                Some(token_ranges.into_iter().map(move |range| {
+                    (
+                        lsp_range_to_kakoune(&range, &document.text, server.offset_encoding),
+                        token_modifiers.len(),
+                        best.face.as_str(),
+                    )
+                }))
             },
         )
-        .collect::<Vec<String>>()
-        .join(" ");
+        .flatten()
+        .collect();
 
-    let version = meta.version;
-    let command = format!("set-option buffer lsp_semantic_tokens_ranges {version} {ranges}");
-    let command = format!(
-        "evaluate-commands -buffer {} -verbatim -- {}",
-        editor_quote(&meta.buffile),
-        &command
-    );
-    ctx.exec(meta, command)
+    // Kakoune applies overlapping ranges in order, each face on top of the previous ones.
+    // Put enclosing tokens before the tokens they contain, and among tokens with the same
+    // range, the ones with more modifiers last, so the most specific face wins.
+    // The sort is stable, so the server's order breaks the remaining ties.
+    ranges.sort_by_key(|(range, modifiers, _)| {
+        (range.start, std::cmp::Reverse(range.end), *modifiers)
+    });
+    ranges
+        .into_iter()
+        .map(|(range, _, face)| format!("{}|{}", ForwardKakouneRange(range), face))
+        .collect()
+}
+
+/// Split a token into one range per line, since multiline tokens may span line breaks.
+/// The length counts the line terminators in the server's offset encoding.
+fn token_line_ranges(
+    line: u32,
+    start: u32,
+    length: u32,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Vec<Range> {
+    let Some(line_text) = text.get_line(line as usize) else {
+        return vec![];
+    };
+    let Some(start_byte) = lsp_character_to_byte_offset(line_text, start as usize, offset_encoding)
+    else {
+        return vec![];
+    };
+    let start_char = text.byte_to_char(text.line_to_byte(line as usize) + start_byte);
+    let end_char = match offset_encoding {
+        OffsetEncoding::Utf8 => text
+            .byte_to_char((text.char_to_byte(start_char) + length as usize).min(text.len_bytes())),
+        OffsetEncoding::Utf16 => text.utf16_cu_to_char(
+            (text.char_to_utf16_cu(start_char) + length as usize).min(text.len_utf16_cu()),
+        ),
+    };
+    (line as usize..=text.char_to_line(end_char))
+        .filter_map(|line| {
+            let line_start = text.line_to_char(line);
+            let mut line_end = line_start + text.line(line).len_chars();
+            while line_end > line_start && matches!(text.char(line_end - 1), '\n' | '\r') {
+                line_end -= 1;
+            }
+            let from = start_char.max(line_start);
+            let to = end_char.min(line_end);
+            (from < to).then(|| Range {
+                start: char_index_to_lsp_position(from, text, offset_encoding),
+                end: char_index_to_lsp_position(to, text, offset_encoding),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(delta_line: u32) -> SemanticToken {
+        SemanticToken {
+            delta_line,
+            delta_start: 0,
+            length: 1,
+            token_type: 0,
+            token_modifiers_bitset: 0,
+        }
+    }
+
+    #[test]
+    fn apply_semantic_tokens_edits_back_to_front() {
+        let data = vec![token(0), token(1), token(2), token(3)];
+        let edits = vec![
+            SemanticTokensEdit {
+                start: 5,
+                delete_count: 5,
+                data: Some(vec![token(10), token(11)]),
+            },
+            SemanticTokensEdit {
+                start: 15,
+                delete_count: 5,
+                data: None,
+            },
+        ];
+        assert_eq!(
+            apply_semantic_tokens_edits(&data, edits),
+            Some(vec![token(0), token(10), token(11), token(2)])
+        );
+        let misaligned = vec![SemanticTokensEdit {
+            start: 5,
+            delete_count: 3,
+            data: None,
+        }];
+        assert_eq!(apply_semantic_tokens_edits(&data, misaligned), None);
+    }
+
+    #[test]
+    fn token_line_ranges_splits_multiline_tokens() {
+        let text = Rope::from_str("ab\r\ncd\n");
+        assert_eq!(
+            token_line_ranges(0, 1, 5, &text, OffsetEncoding::Utf8),
+            vec![
+                Range::new(Position::new(0, 1), Position::new(0, 2)),
+                Range::new(Position::new(1, 0), Position::new(1, 2)),
+            ]
+        );
+    }
 }
@@ -1,15 +1,41 @@
 use crate::capabilities::attempt_server_capability;
 use crate::capabilities::CAPABILITY_SIGNATURE_HELP;
 use crate::context::*;
-use crate::markup::escape_kakoune_markup;
+use crate::markup::*;
 use crate::position::*;
 use crate::types::*;
 use crate::util::*;
+use itertools::Itertools;
 use lsp_types::request::*;
 use lsp_types::*;
 use ropey::Rope;
 
-pub fn text_document_signature_help(meta: EditorMeta, params: PositionParams, ctx: &mut Context) {
+#[derive(Debug)]
+pub struct EditorSignatureHelpParams {
+    pub position: KakounePosition,
+    // The typed character that triggered the request, or empty if it was invoked.
+    pub trigger_character: String,
+    // Whether the signature help is already shown.
+    pub is_retrigger: bool,
+}
+
+#[derive(Debug)]
+pub struct SignatureHelpCycleParams {
+    pub step: i32,
+}
+
+/// The signature help shown in a Kakoune client, with the signature chosen by the user.
+pub struct SignatureHelpState {
+    server_id: ServerId,
+    position: KakounePosition,
+    help: SignatureHelp,
+}
+
+pub fn text_document_signature_help(
+    meta: EditorMeta,
+    params: EditorSignatureHelpParams,
+    ctx: &mut Context,
+) {
     let eligible_servers: Vec<_> = ctx
         .servers(&meta)
         .filter(|srv| attempt_server_capability(ctx, *srv, &meta, CAPABILITY_SIGNATURE_HELP))
@@ -21,13 +47,34 @@ pub fn text_document_signature_help(meta: EditorMeta, params: PositionParams, ct
     let (first_server, _) = *eligible_servers.first().unwrap();
     let first_server = first_server.to_owned();
 
+    let active_signature_help = if params.is_retrigger {
+        meta.client
+            .as_ref()
+            .and_then(|client| ctx.signature_help.get(client))
+            .map(|state| state.help.clone())
+    } else {
+        None
+    };
+    let trigger_character =
+        Some(params.trigger_character.clone()).filter(|trigger| !trigger.is_empty());
+    let context = SignatureHelpContext {
+        trigger_kind: if trigger_character.is_some() {
+            SignatureHelpTriggerKind::TRIGGER_CHARACTER
+        } else {
+            SignatureHelpTriggerKind::INVOKED
+        },
+        trigger_character,
+        is_retrigger: params.is_retrigger,
+        active_signature_help,
+    };
+
     let req_params = eligible_servers
         .into_iter()
         .map(|(server_id, server_settings)| {
             (
                 server_id,
                 vec![SignatureHelpParams {
-                    context: None,
+                    context: Some(context.clone()),
                     text_document_position_params: TextDocumentPositionParams {
                         text_document: TextDocumentIdentifier {
                             uri: file_path_to_uri(&meta.buffile),
@@ -54,83 +101,203 @@ pub fn text_document_signature_help(meta: EditorMeta, params: PositionParams, ct
                 None => (first_server, None),
             };
 
-            editor_signature_help(meta, params, result, ctx)
+            editor_signature_help(meta, params.position, result, ctx)
         },
     );
 }
 
 fn editor_signature_help(
     meta: EditorMeta,
-    params: PositionParams,
+    position: KakounePosition,
     result: (ServerId, Option<SignatureHelp>),
     ctx: &mut Context,
 ) {
+    let Some(client) = meta.client.clone() else {
+        return;
+    };
     let (server_id, result) = result;
-    let result = match result {
-        Some(result) => result,
-        None => return,
+    let help = match result {
+        Some(help) if !help.signatures.is_empty() => help,
+        _ => {
+            // Stop retriggering once there is no signature to show.
+            if ctx.signature_help.remove(&client).is_some() {
+                ctx.exec(meta, "remove-hooks window lsp-signature-help-retrigger");
+            }
+            return;
+        }
     };
 
-    let active_signature = result.active_signature.unwrap_or(0);
+    let server = ctx.server(server_id);
+    let retrigger_characters = server
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.signature_help_provider.as_ref())
+        .map(|provider| {
+            provider
+                .trigger_characters
+                .iter()
+                .chain(&provider.retrigger_characters)
+                .flatten()
+                .flat_map(|s| s.chars())
+                .unique()
+                .collect::<String>()
+        })
+        .unwrap_or_default();
 
-    let active_signature = match result.signatures.get(active_signature as usize) {
-        Some(active_signature) => active_signature,
-        None => return,
+    ctx.signature_help.insert(
+        client.clone(),
+        SignatureHelpState {
+            server_id,
+            position,
+            help,
+        },
+    );
+    let mut command = show_signature_help(&ctx.signature_help[&client], ctx);
+    if !retrigger_characters.is_empty() {
+        command.push_str(&format!(
+            "\nlsp-signature-help-retrigger-on {}",
+            editor_quote(&character_class(&retrigger_characters))
+        ));
+    }
+    ctx.exec(meta, command);
+}
+
+/// Show the previous or next signature.
+pub fn signature_help_cycle(meta: EditorMeta, params: SignatureHelpCycleParams, ctx: &mut Context) {
+    let Some(state) = meta
+        .client
+        .as_ref()
+        .and_then(|client| ctx.signature_help.get_mut(client))
+    else {
+        return;
     };
+    let count = state.help.signatures.len() as i32;
+    let active = state.help.active_signature.unwrap_or(0) as i32;
+    state.help.active_signature = Some((active + params.step).rem_euclid(count) as u32);
+    // The active parameter of the response applies to the active signature of the server.
+    state.help.active_parameter = None;
+    let state = &ctx.signature_help[meta.client.as_ref().unwrap()];
+    let command = show_signature_help(state, ctx);
+    ctx.exec(meta, command);
+}
 
-    let server = ctx.server(server_id);
-    let active_parameter = active_signature
+fn show_signature_help(state: &SignatureHelpState, ctx: &Context) -> String {
+    let help = &state.help;
+    let active_signature = help.active_signature.unwrap_or(0) as usize;
+    let active_signature = active_signature.min(help.signatures.len() - 1);
+    let signature = &help.signatures[active_signature];
+
+    let active_parameter = signature
         .active_parameter
-        .or(result.active_parameter)
+        .or(help.active_parameter)
         .unwrap_or(0);
-    let parameter_range = match active_signature
+    let parameter = signature
         .parameters
         .as_ref()
-        .and_then(|p| p.get(active_parameter as usize))
-        .map(|p| &p.label)
-    {
-        Some(ParameterLabel::Simple(param)) => active_signature
-            .label
-            .find(param.as_str())
-            .map(|begin| [begin, begin + param.len()]),
-        Some(ParameterLabel::LabelOffsets(offsets)) => {
-            let label = Rope::from_str(&active_signature.label);
-            let begin = lsp_character_to_byte_offset(
-                label.slice(..),
-                offsets[0] as usize,
-                server.offset_encoding,
-            )
-            .unwrap();
-            let end = lsp_character_to_byte_offset(
-                label.slice(..),
-                offsets[1] as usize,
-                server.offset_encoding,
-            )
-            .unwrap();
-            Some([begin, end])
-        }
-        None => None,
-    };
+        .and_then(|p| p.get(active_parameter as usize));
+    let offset_encoding = ctx.server(state.server_id).offset_encoding;
+    let parameter_range =
+        parameter.and_then(|p| parameter_range(&signature.label, &p.label, offset_encoding));
 
-    let mut contents = active_signature.label.clone();
-    if let Some(range) = parameter_range {
-        if range[0] >= contents.len() || range[1] >= contents.len() {
-            warn!(ctx.to_editor(), "invalid range for active parameter");
-        } else {
-            let (left, tail) = contents.split_at(range[0]);
-            let (param, right) = tail.split_at(range[1] - range[0]);
-            contents = escape_kakoune_markup(left)
-                + "{+b}"
-                + &escape_kakoune_markup(param)
+    let mut contents = match parameter_range {
+        Some([begin, end]) => {
+            let label = &signature.label;
+            escape_kakoune_markup(&label[..begin])
+                + "{SignatureHelpActiveParameter}"
+                + &escape_kakoune_markup(&label[begin..end])
                 + "{}"
-                + &escape_kakoune_markup(right)
+                + &escape_kakoune_markup(&label[end..])
         }
+        None => escape_kakoune_markup(&signature.label),
     };
+    if help.signatures.len() > 1 {
+        contents.push_str(&format!(
+            " {{MenuInfo}}({}/{}){{}}",
+            active_signature + 1,
+            help.signatures.len()
+        ));
+    }
+    if let Some(documentation) = parameter.and_then(|p| p.documentation.as_ref()) {
+        contents.push('\n');
+        contents.push_str(&documentation_markup(ctx, documentation));
+    }
+    if let Some(documentation) = signature.documentation.as_ref() {
+        contents.push_str("\n\n");
+        contents.push_str(&documentation_markup(ctx, documentation));
+    }
 
-    let command = format!(
+    format!(
         "lsp-show-signature-help {} {}",
-        params.position,
+        state.position,
         editor_quote(&contents)
-    );
-    ctx.exec(meta, command);
+    )
+}
+
+/// The byte range of a parameter within the signature label.
+fn parameter_range(
+    label: &str,
+    parameter: &ParameterLabel,
+    offset_encoding: OffsetEncoding,
+) -> Option<[usize; 2]> {
+    match parameter {
+        ParameterLabel::Simple(param) => {
+            // Skip the function name, which may contain the parameter name.
+            let params_start = label.find('(').map_or(0, |paren| paren + 1);
+            label[params_start..]
+                .find(param.as_str())
+                .map(|begin| [params_start + begin, params_start + begin + param.len()])
+        }
+        ParameterLabel::LabelOffsets([begin, end]) => {
+            let rope = Rope::from_str(label);
+            let begin =
+                lsp_character_to_byte_offset(rope.slice(..), *begin as usize, offset_encoding)?;
+            let end = lsp_character_to_byte_offset(rope.slice(..), *end as usize, offset_encoding)?;
+            (begin <= end).then_some([begin, end])
+        }
+    }
+}
+
+fn documentation_markup(ctx: &Context, documentation: &Documentation) -> String {
+    match documentation {
+        Documentation::String(s) => escape_kakoune_markup(s),
+        Documentation::MarkupContent(content) => match content.kind {
+            MarkupKind::PlainText => escape_kakoune_markup(&content.value),
+            MarkupKind::Markdown => markdown_to_kakoune_markup(ctx.to_editor(), &content.value),
+        },
+    }
+}
+
+/// A regex character class matching any of the given characters.
+fn character_class(characters: &str) -> String {
+    let mut class = "[".to_string();
+    for c in characters.chars() {
+        if matches!(c, '\\' | ']' | '[' | '^' | '-') {
+            class.push('\\');
+        }
+        class.push(c);
+    }
+    class.push(']');
+    class
+}
+
+/// Forget the signature help shown in a client that was closed.
+pub fn client_close(client: &ClientId, ctx: &mut Context) {
+    ctx.signature_help.remove(client);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parameter_range_skips_function_name() {
+        let label = "fn foo(f: u8, bar: &str)";
+        let range = |parameter| parameter_range(label, &parameter, OffsetEncoding::Utf16);
+        assert_eq!(range(ParameterLabel::Simple("f".to_string())), Some([7, 8]));
+        assert_eq!(
+            range(ParameterLabel::LabelOffsets([14, 23])),
+            Some([14, 23])
+        );
+        assert_eq!(range(ParameterLabel::LabelOffsets([14, 99])), None);
+    }
 }
@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+
+use crate::context::*;
+use crate::position::*;
+use crate::types::*;
+use crate::util::uri_to_file_path;
+use crate::util::*;
+use lsp_types::{request::*, *};
+
+struct TypeHierarchyNode {
+    item: TypeHierarchyItem,
+    level: u32,
+    children: Vec<usize>,
+}
+
+// lsp-types does not expose the server's typeHierarchyProvider capability, so like the call
+// hierarchy we ask all servers and use the first one that answers.
+pub fn type_hierarchy_prepare(meta: EditorMeta, params: TypeHierarchyParams, ctx: &mut Context) {
+    let req_params = ctx
+        .servers(&meta)
+        .map(|(server_id, server_settings)| {
+            let position =
+                get_lsp_position(server_settings, &meta.buffile, &params.position, ctx).unwrap();
+            let uri = file_path_to_uri(&meta.buffile);
+            (
+                server_id,
+                vec![TypeHierarchyPrepareParams {
+                    text_document_position_params: TextDocumentPositionParams {
+                        text_document: TextDocumentIdentifier::new(uri),
+                        position,
+                    },
+                    work_done_progress_params: WorkDoneProgressParams::default(),
+                }],
+            )
+        })
+        .collect();
+
+    ctx.call::<TypeHierarchyPrepare, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx: &mut Context, meta, results| {
+            let Some((server_id, item)) = results
+                .into_iter()
+                .find_map(|(server_id, items)| Some((server_id, items?.into_iter().next()?)))
+            else {
+                return;
+            };
+            let nodes = vec![TypeHierarchyNode {
+                item,
+                level: 0,
+                children: vec![],
+            }];
+            expand_type_hierarchy(meta, ctx, server_id, params, nodes, vec![0]);
+        },
+    )
+}
+
+/// Request the supertypes or subtypes of the node on top of the stack, until all nodes up to
+/// the requested depth have been expanded.
+fn expand_type_hierarchy(
+    meta: EditorMeta,
+    ctx: &mut Context,
+    server_id: ServerId,
+    params: TypeHierarchyParams,
+    mut nodes: Vec<TypeHierarchyNode>,
+    mut stack: Vec<usize>,
+) {
+    let Some(parent) = stack.pop() else {
+        format_type_hierarchy(meta, ctx, server_id, params.supertypes_or_subtypes, &nodes);
+        return;
+    };
+    let item = nodes[parent].item.clone();
+    let supertypes_or_subtypes = params.supertypes_or_subtypes;
+    let callback =
+        move |ctx: &mut Context, meta, results: Vec<(ServerId, Option<Vec<TypeHierarchyItem>>)>| {
+            let items = results
+                .into_iter()
+                .next()
+                .and_then(|(_, items)| items)
+                .unwrap_or_default();
+            let level = nodes[parent].level + 1;
+            for item in items {
+                let index = nodes.len();
+                nodes[parent].children.push(index);
+                nodes.push(TypeHierarchyNode {
+                    item,
+                    level,
+                    children: vec![],
+                });
+            }
+            if level < params.depth {
+                stack.extend(nodes[parent].children.iter().rev());
+            }
+            expand_type_hierarchy(meta, ctx, server_id, params, nodes, stack);
+        };
+
+    if supertypes_or_subtypes {
+        let mut req_params = HashMap::new();
+        req_params.insert(
+            server_id,
+            vec![TypeHierarchySupertypesParams {
+                item,
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+            }],
+        );
+        ctx.call::<TypeHierarchySupertypes, _>(meta, RequestParams::Each(req_params), callback);
+    } else {
+        let mut req_params = HashMap::new();
+        req_params.insert(
+            server_id,
+            vec![TypeHierarchySubtypesParams {
+                item,
+                work_done_progress_params: WorkDoneProgressParams::default(),
+                partial_result_params: PartialResultParams::default(),
+            }],
+        );
+        ctx.call::<TypeHierarchySubtypes, _>(meta, RequestParams::Each(req_params), callback);
+    }
+}
+
+fn format_type_hierarchy(
+    meta: EditorMeta,
+    ctx: &mut Context,
+    server_id: ServerId,
+    supertypes_or_subtypes: bool,
+    nodes: &[TypeHierarchyNode],
+) {
+    let server = ctx.server(server_id);
+    let format_item = |item: &TypeHierarchyItem, indent: usize, suffix: &str| {
+        let path = uri_to_file_path(&item.uri);
+        let path = path.to_str().unwrap();
+        let filename = short_file_path(path, ctx.main_root(&meta));
+        let position =
+            get_kakoune_position_with_fallback(server, path, item.selection_range.start, ctx);
+        let detail = item
+            .detail
+            .as_ref()
+            .map(|detail| format!(" {}", detail))
+            .unwrap_or_default();
+        format!(
+            "{}{}:{}:{}: {}{}{}\n",
+            " ".repeat(indent),
+            filename,
+            position.line,
+            position.column,
+            item.name,
+            detail,
+            suffix,
+        )
+    };
+
+    let mut contents = format_item(
+        &nodes[0].item,
+        0,
+        if supertypes_or_subtypes {
+            " - list of supertypes"
+        } else {
+            " - list of subtypes"
+        },
+    );
+    let mut stack: Vec<_> = nodes[0].children.iter().rev().collect();
+    while let Some(&index) = stack.pop() {
+        let node = &nodes[index];
+        contents += &format_item(&node.item, 2 * node.level as usize, "");
+        stack.extend(node.children.iter().rev());
+    }
+
+    let command = if supertypes_or_subtypes {
+        "lsp-show-supertypes"
+    } else {
+        "lsp-show-subtypes"
+    };
+    let command = format!(
+        "{} {} {}",
+        command,
+        editor_quote(ctx.main_root(&meta)),
+        editor_quote(&contents),
+    );
+    ctx.exec(meta, command);
+}
@@ -12,6 +12,8 @@ mod capabilities;
 mod context;
 mod controller;
 mod diagnostics;
+mod edit_journal;
+mod edit_preview;
 mod editor_transport;
 mod language_features;
 mod language_server_transport;
@@ -20,7 +22,9 @@ mod position;
 mod progress;
 mod project_root;
 mod settings;
+mod show_document;
 mod show_message;
+mod snippet;
 mod text_edit;
 mod text_sync;
 mod thread_worker;
@@ -386,6 +390,9 @@ fn run_main() -> Result<(), ()> {
         if let Some(file_watch_support) = env_var("kak_opt_lsp_file_watch_support")? {
             config.file_watch_support = file_watch_support != "false";
         }
+        if let Some(workspace_edit_preview) = env_var("kak_opt_lsp_workspace_edit_preview")? {
+            config.workspace_edit_preview = workspace_edit_preview != "false";
+        }
         config
     };
 
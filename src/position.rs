@@ -362,6 +362,26 @@ fn lsp_position_to_kakoune_utf_8(position: &Position) -> KakounePosition {
     }
 }
 
+/// Convert a char index into the given Rope to an LSP position.
+pub fn char_index_to_lsp_position(
+    char_index: usize,
+    text: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Position {
+    let line = text.char_to_line(char_index);
+    let line_start = text.line_to_char(line);
+    let character = match offset_encoding {
+        OffsetEncoding::Utf8 => text.char_to_byte(char_index) - text.char_to_byte(line_start),
+        OffsetEncoding::Utf16 => {
+            text.char_to_utf16_cu(char_index) - text.char_to_utf16_cu(line_start)
+        }
+    };
+    Position {
+        line: line as _,
+        character: character as _,
+    }
+}
+
 pub fn lsp_character_to_byte_offset(
     line: RopeSlice,
     character: usize,
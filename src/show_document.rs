@@ -0,0 +1,75 @@
+use std::path::Path;
+
+use jsonrpc_core::Params;
+use lsp_types::{ShowDocumentParams, ShowDocumentResult};
+use serde_json::Value;
+
+use crate::{
+    context::Context,
+    language_features::goto::edit_at_range,
+    position::{get_file_contents, lsp_range_to_kakoune},
+    types::{EditorMeta, ServerId},
+    util::editor_quote,
+    LAST_CLIENT,
+};
+
+/// Handles a server's request to show a document. Files are opened in the client that sent the
+/// last request; external URIs are passed to the configured opener.
+pub fn show_document(
+    meta: EditorMeta,
+    server_id: ServerId,
+    params: Params,
+    ctx: &mut Context,
+) -> Result<Value, jsonrpc_core::Error> {
+    let params: ShowDocumentParams = params.parse()?;
+    let success = match show_document_command(server_id, &params, ctx) {
+        Some(command) => {
+            ctx.exec(meta, command);
+            true
+        }
+        None => false,
+    };
+    Ok(serde_json::to_value(ShowDocumentResult { success }).unwrap())
+}
+
+fn show_document_command(
+    server_id: ServerId,
+    params: &ShowDocumentParams,
+    ctx: &Context,
+) -> Option<String> {
+    if params.external == Some(true) {
+        return Some(format!(
+            "lsp-open-external {}",
+            editor_quote(params.uri.as_str())
+        ));
+    }
+
+    let client = LAST_CLIENT.lock().unwrap().clone()?;
+    let path = url::Url::parse(params.uri.as_str())
+        .ok()
+        .filter(|url| url.scheme() == "file")?
+        .to_file_path()
+        .ok()?;
+    let path = path.to_str()?;
+    if !Path::new(path).exists() {
+        return None;
+    }
+    let mut command = match &params.selection {
+        Some(selection) => {
+            let contents = get_file_contents(path, ctx)?;
+            let offset_encoding = ctx.server(server_id).offset_encoding;
+            let range = lsp_range_to_kakoune(selection, &contents, offset_encoding);
+            edit_at_range(path, range, true)
+        }
+        None => format!("edit -existing -- {}", editor_quote(path)),
+    };
+    if params.take_focus == Some(true) {
+        // Only windowing modules such as tmux or x11 provide "focus".
+        command.push_str("\ntry focus");
+    }
+    Some(format!(
+        "evaluate-commands -try-client {} -- {}",
+        editor_quote(client.as_str()),
+        editor_quote(&command)
+    ))
+}
@@ -0,0 +1,810 @@
+//! Parsing and expansion of the LSP snippet syntax, see
+//! https://microsoft.github.io/language-server-protocol/specifications/specification-current/#snippet_syntax
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use itertools::Itertools;
+use rand::Rng;
+use regex::{Captures, Regex};
+
+use crate::context::Context;
+use crate::types::EditorMeta;
+use crate::util::{editor_quote, short_file_path};
+
+#[derive(Debug, PartialEq)]
+pub enum Element {
+    Text(String),
+    Tabstop {
+        index: u32,
+        placeholder: Vec<Element>,
+        choices: Vec<String>,
+        transform: Option<Transform>,
+    },
+    Variable {
+        name: String,
+        default: Option<Vec<Element>>,
+        transform: Option<Transform>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Transform {
+    regex: String,
+    format: Vec<FormatItem>,
+    options: String,
+}
+
+#[derive(Debug, PartialEq)]
+enum FormatItem {
+    Text(String),
+    Group(usize),
+    Case(usize, String),
+    // Without an if text, a matched group is inserted as is.
+    Conditional {
+        group: usize,
+        if_text: Option<String>,
+        else_text: String,
+    },
+}
+
+/// Parse a snippet. Malformed constructs are kept as text.
+pub fn parse(snippet: &str) -> Vec<Element> {
+    let mut parser = Parser {
+        chars: snippet.chars().collect(),
+        pos: 0,
+    };
+    parser.any(&[])
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn int(&mut self) -> Option<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos]
+            .iter()
+            .collect::<String>()
+            .parse()
+            .ok()
+    }
+
+    fn var_name(&mut self) -> Option<String> {
+        if !self
+            .peek()
+            .is_some_and(|c| c == '_' || c.is_ascii_alphabetic())
+        {
+            return None;
+        }
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c == '_' || c.is_ascii_alphanumeric())
+        {
+            self.pos += 1;
+        }
+        Some(self.chars[start..self.pos].iter().collect())
+    }
+
+    /// Text and snippet elements up to one of the stop characters.
+    fn any(&mut self, stop: &[char]) -> Vec<Element> {
+        let mut elements = vec![];
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if stop.contains(&c) {
+                break;
+            }
+            if c == '$' {
+                let start = self.pos;
+                if let Some(element) = self.dollar() {
+                    if !text.is_empty() {
+                        elements.push(Element::Text(std::mem::take(&mut text)));
+                    }
+                    elements.push(element);
+                    continue;
+                }
+                self.pos = start + 1;
+                text.push(c);
+                continue;
+            }
+            self.pos += 1;
+            if c == '\\' {
+                if let Some(escaped) = self.peek().filter(|c| matches!(c, '$' | '}' | '\\')) {
+                    self.pos += 1;
+                    text.push(escaped);
+                    continue;
+                }
+            }
+            text.push(c);
+        }
+        if !text.is_empty() {
+            elements.push(Element::Text(text));
+        }
+        elements
+    }
+
+    fn dollar(&mut self) -> Option<Element> {
+        self.pos += 1;
+        if let Some(index) = self.int() {
+            return Some(tabstop(index, vec![], vec![], None));
+        }
+        if let Some(name) = self.var_name() {
+            return Some(Element::Variable {
+                name,
+                default: None,
+                transform: None,
+            });
+        }
+        if !self.eat('{') {
+            return None;
+        }
+        if let Some(index) = self.int() {
+            let element = if self.eat(':') {
+                tabstop(index, self.any(&['}']), vec![], None)
+            } else if self.eat('|') {
+                tabstop(index, vec![], self.choices()?, None)
+            } else if self.eat('/') {
+                tabstop(index, vec![], vec![], Some(self.transform()?))
+            } else {
+                tabstop(index, vec![], vec![], None)
+            };
+            return self.eat('}').then_some(element);
+        }
+        let name = self.var_name()?;
+        let (default, transform) = if self.eat(':') {
+            (Some(self.any(&['}'])), None)
+        } else if self.eat('/') {
+            (None, Some(self.transform()?))
+        } else {
+            (None, None)
+        };
+        self.eat('}').then_some(Element::Variable {
+            name,
+            default,
+            transform,
+        })
+    }
+
+    fn choices(&mut self) -> Option<Vec<String>> {
+        let mut choices = vec![];
+        let mut choice = String::new();
+        loop {
+            let c = self.peek()?;
+            self.pos += 1;
+            match c {
+                ',' => choices.push(std::mem::take(&mut choice)),
+                '|' => {
+                    choices.push(choice);
+                    return Some(choices);
+                }
+                '\\' if self
+                    .peek()
+                    .is_some_and(|c| matches!(c, ',' | '|' | '$' | '}' | '\\')) =>
+                {
+                    choice.push(self.peek().unwrap());
+                    self.pos += 1;
+                }
+                c => choice.push(c),
+            }
+        }
+    }
+
+    fn transform(&mut self) -> Option<Transform> {
+        let mut regex = String::new();
+        loop {
+            let c = self.peek()?;
+            self.pos += 1;
+            match c {
+                '/' => break,
+                '\\' if self.eat('/') => regex.push('/'),
+                '\\' => {
+                    regex.push(c);
+                    regex.push(self.peek()?);
+                    self.pos += 1;
+                }
+                c => regex.push(c),
+            }
+        }
+        let format = self.format()?;
+        let mut options = String::new();
+        while let Some(c) = self.peek().filter(|c| c.is_ascii_alphabetic()) {
+            options.push(c);
+            self.pos += 1;
+        }
+        Some(Transform {
+            regex,
+            format,
+            options,
+        })
+    }
+
+    fn format(&mut self) -> Option<Vec<FormatItem>> {
+        let mut items = vec![];
+        let mut text = String::new();
+        loop {
+            let c = self.peek()?;
+            self.pos += 1;
+            let item = match c {
+                '/' => break,
+                '\\' if self.peek().is_some_and(|c| matches!(c, '/' | '$' | '\\')) => {
+                    text.push(self.peek().unwrap());
+                    self.pos += 1;
+                    continue;
+                }
+                '$' => {
+                    let start = self.pos;
+                    match self.format_item() {
+                        Some(item) => item,
+                        None => {
+                            self.pos = start;
+                            text.push(c);
+                            continue;
+                        }
+                    }
+                }
+                c => {
+                    text.push(c);
+                    continue;
+                }
+            };
+            if !text.is_empty() {
+                items.push(FormatItem::Text(std::mem::take(&mut text)));
+            }
+            items.push(item);
+        }
+        if !text.is_empty() {
+            items.push(FormatItem::Text(text));
+        }
+        Some(items)
+    }
+
+    fn format_item(&mut self) -> Option<FormatItem> {
+        if let Some(group) = self.int() {
+            return Some(FormatItem::Group(group as usize));
+        }
+        if !self.eat('{') {
+            return None;
+        }
+        let group = self.int()? as usize;
+        if self.eat('}') {
+            return Some(FormatItem::Group(group));
+        }
+        if !self.eat(':') {
+            return None;
+        }
+        let item = if self.eat('/') {
+            FormatItem::Case(group, self.var_name()?)
+        } else if self.eat('+') {
+            FormatItem::Conditional {
+                group,
+                if_text: Some(self.until(&['}'])?),
+                else_text: String::new(),
+            }
+        } else if self.eat('?') {
+            let if_text = self.until(&[':'])?;
+            self.pos += 1;
+            FormatItem::Conditional {
+                group,
+                if_text: Some(if_text),
+                else_text: self.until(&['}'])?,
+            }
+        } else {
+            self.eat('-');
+            FormatItem::Conditional {
+                group,
+                if_text: None,
+                else_text: self.until(&['}'])?,
+            }
+        };
+        self.eat('}').then_some(item)
+    }
+
+    /// Text up to, but not including, one of the stop characters.
+    fn until(&mut self, stop: &[char]) -> Option<String> {
+        let mut text = String::new();
+        loop {
+            let c = self.peek()?;
+            if stop.contains(&c) {
+                return Some(text);
+            }
+            self.pos += 1;
+            if c == '\\' {
+                text.push(self.peek()?);
+                self.pos += 1;
+            } else {
+                text.push(c);
+            }
+        }
+    }
+}
+
+fn tabstop(
+    index: u32,
+    placeholder: Vec<Element>,
+    choices: Vec<String>,
+    transform: Option<Transform>,
+) -> Element {
+    Element::Tabstop {
+        index,
+        placeholder,
+        choices,
+        transform,
+    }
+}
+
+impl Transform {
+    fn apply(&self, value: &str) -> String {
+        let flags: String = self
+            .options
+            .chars()
+            .filter(|c| matches!(c, 'i' | 'm' | 's'))
+            .collect();
+        let pattern = if flags.is_empty() {
+            self.regex.clone()
+        } else {
+            format!("(?{flags}){}", self.regex)
+        };
+        let Ok(regex) = Regex::new(&pattern) else {
+            return value.to_string();
+        };
+        let global = self.options.contains('g');
+        let mut result = String::new();
+        let mut last = 0;
+        for captures in regex.captures_iter(value) {
+            let matched = captures.get(0).unwrap();
+            result.push_str(&value[last..matched.start()]);
+            for item in &self.format {
+                result.push_str(&item.format(&captures));
+            }
+            last = matched.end();
+            if !global {
+                break;
+            }
+        }
+        result.push_str(&value[last..]);
+        result
+    }
+}
+
+impl FormatItem {
+    fn format(&self, captures: &Captures) -> String {
+        let group = |group: usize| captures.get(group).map_or("", |m| m.as_str());
+        match self {
+            FormatItem::Text(text) => text.clone(),
+            FormatItem::Group(n) => group(*n).to_string(),
+            FormatItem::Case(n, case) => change_case(group(*n), case),
+            FormatItem::Conditional {
+                group: n,
+                if_text,
+                else_text,
+            } => {
+                let value = group(*n);
+                if value.is_empty() {
+                    else_text.clone()
+                } else {
+                    if_text.clone().unwrap_or_else(|| value.to_string())
+                }
+            }
+        }
+    }
+}
+
+fn change_case(value: &str, case: &str) -> String {
+    fn capitalize(word: &str) -> String {
+        let mut chars = word.chars();
+        chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect())
+            .unwrap_or_default()
+    }
+    let words = || {
+        value
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+    };
+    match case {
+        "upcase" => value.to_uppercase(),
+        "downcase" => value.to_lowercase(),
+        "capitalize" => capitalize(value),
+        "pascalcase" => words().map(capitalize).collect(),
+        "camelcase" => words()
+            .enumerate()
+            .map(|(i, word)| {
+                if i == 0 {
+                    let mut chars = word.chars();
+                    chars
+                        .next()
+                        .map(|first| first.to_lowercase().chain(chars).collect())
+                        .unwrap_or_default()
+                } else {
+                    capitalize(word)
+                }
+            })
+            .collect(),
+        _ => value.to_string(),
+    }
+}
+
+enum Piece {
+    Text(String),
+    Tabstop(u32, String),
+}
+
+/// A snippet with its variables resolved, and the text of each tabstop.
+pub struct Expansion {
+    pieces: Vec<Piece>,
+    choices: HashMap<u32, Vec<String>>,
+}
+
+/// Expand a snippet. Placeholders nested in other placeholders become plain text, and
+/// unknown variables are replaced by their names.
+pub fn expand(snippet: &str, variables: &dyn Fn(&str) -> Option<String>) -> Expansion {
+    let elements = parse(snippet);
+    let mut definitions = HashMap::new();
+    find_definitions(&elements, &mut definitions);
+    let expander = Expander {
+        definitions,
+        variables,
+    };
+    let mut pieces = vec![];
+    expander.pieces(&elements, &mut pieces);
+    let choices = expander
+        .definitions
+        .iter()
+        .filter_map(|(index, element)| match element {
+            Element::Tabstop { choices, .. } if !choices.is_empty() => {
+                Some((*index, choices.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+    Expansion { pieces, choices }
+}
+
+/// The first occurrence of each tabstop that has a placeholder or choices defines its text.
+fn find_definitions<'a>(elements: &'a [Element], definitions: &mut HashMap<u32, &'a Element>) {
+    for element in elements {
+        match element {
+            Element::Text(_) => (),
+            Element::Tabstop {
+                index,
+                placeholder,
+                choices,
+                ..
+            } => {
+                if !placeholder.is_empty() || !choices.is_empty() {
+                    definitions.entry(*index).or_insert(element);
+                }
+                find_definitions(placeholder, definitions);
+            }
+            Element::Variable { default, .. } => {
+                if let Some(default) = default {
+                    find_definitions(default, definitions);
+                }
+            }
+        }
+    }
+}
+
+struct Expander<'a> {
+    definitions: HashMap<u32, &'a Element>,
+    variables: &'a dyn Fn(&str) -> Option<String>,
+}
+
+impl<'a> Expander<'a> {
+    fn pieces(&self, elements: &[Element], pieces: &mut Vec<Piece>) {
+        for element in elements {
+            match element {
+                Element::Text(text) => pieces.push(Piece::Text(text.clone())),
+                // Kakoune has no mirrors with transforms, so those are inserted as text.
+                Element::Tabstop {
+                    index,
+                    transform: None,
+                    ..
+                } => pieces.push(Piece::Tabstop(
+                    *index,
+                    self.tabstop_text(*index, &mut vec![]),
+                )),
+                Element::Tabstop { .. } => {
+                    let mut text = String::new();
+                    self.text(std::slice::from_ref(element), &mut text, &mut vec![]);
+                    pieces.push(Piece::Text(text));
+                }
+                Element::Variable {
+                    name,
+                    default,
+                    transform,
+                } => match ((self.variables)(name), default) {
+                    (Some(value), _) if !value.is_empty() => pieces.push(Piece::Text(
+                        transform
+                            .as_ref()
+                            .map_or(value.clone(), |transform| transform.apply(&value)),
+                    )),
+                    (_, Some(default)) => self.pieces(default, pieces),
+                    (Some(_), None) => (),
+                    (None, None) => pieces.push(Piece::Text(name.clone())),
+                },
+            }
+        }
+    }
+
+    fn text(&self, elements: &[Element], text: &mut String, visiting: &mut Vec<u32>) {
+        for element in elements {
+            match element {
+                Element::Text(s) => text.push_str(s),
+                Element::Tabstop {
+                    index, transform, ..
+                } => {
+                    let value = self.tabstop_text(*index, visiting);
+                    match transform {
+                        Some(transform) => text.push_str(&transform.apply(&value)),
+                        None => text.push_str(&value),
+                    }
+                }
+                Element::Variable {
+                    name,
+                    default,
+                    transform,
+                } => match ((self.variables)(name), default) {
+                    (Some(value), _) if !value.is_empty() => text.push_str(
+                        &transform
+                            .as_ref()
+                            .map_or(value.clone(), |transform| transform.apply(&value)),
+                    ),
+                    (_, Some(default)) => self.text(default, text, visiting),
+                    (Some(_), None) => (),
+                    (None, None) => text.push_str(name),
+                },
+            }
+        }
+    }
+
+    fn tabstop_text(&self, index: u32, visiting: &mut Vec<u32>) -> String {
+        // A placeholder may not contain its own tabstop.
+        if visiting.contains(&index) {
+            return String::new();
+        }
+        let mut text = String::new();
+        if let Some(Element::Tabstop {
+            placeholder,
+            choices,
+            ..
+        }) = self.definitions.get(&index)
+        {
+            match choices.first() {
+                Some(choice) => text.push_str(choice),
+                None => {
+                    visiting.push(index);
+                    self.text(placeholder, &mut text, visiting);
+                    visiting.pop();
+                }
+            }
+        }
+        text
+    }
+}
+
+impl Expansion {
+    /// The text to insert when tabstops are not supported.
+    pub fn text(&self) -> String {
+        self.pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Text(text) | Piece::Tabstop(_, text) => text.as_str(),
+            })
+            .collect()
+    }
+
+    /// Tabstops renumbered from 1 in their original order, with the final tabstop as 0.
+    fn numbers(&self) -> BTreeMap<u32, u32> {
+        self.pieces
+            .iter()
+            .filter_map(|piece| match piece {
+                Piece::Tabstop(index, _) if *index != 0 => Some(*index),
+                _ => None,
+            })
+            .sorted()
+            .dedup()
+            .zip(1..)
+            .collect()
+    }
+
+    /// The snippet in the flat syntax of lsp-snippets-insert, which only supports tabstops
+    /// and placeholders with text.
+    pub fn editor_snippet(&self) -> String {
+        fn escape(text: &str) -> String {
+            text.replace('\\', "\\\\")
+                .replace('$', "\\$")
+                .replace('}', "\\}")
+        }
+        let numbers = self.numbers();
+        let mut snippet = String::new();
+        let mut has_final_tabstop = false;
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => snippet.push_str(&escape(text)),
+                Piece::Tabstop(index, text) => {
+                    let number = numbers.get(index).copied().unwrap_or(0);
+                    has_final_tabstop |= number == 0;
+                    if text.is_empty() {
+                        snippet.push_str(&format!("${number}"));
+                    } else {
+                        snippet.push_str(&format!("${{{number}:{}}}", escape(text)));
+                    }
+                }
+            }
+        }
+        if !has_final_tabstop {
+            snippet.push_str("$0");
+        }
+        snippet
+    }
+
+    /// The choices for lsp_snippets_choices: the placeholder group, which counts tabstops
+    /// from 0, the number of choices and the choices, for each tabstop with choices.
+    pub fn editor_choices(&self) -> String {
+        self.numbers()
+            .into_iter()
+            .filter_map(|(index, number)| {
+                let choices = self.choices.get(&index)?;
+                Some(
+                    [(number - 1).to_string(), choices.len().to_string()]
+                        .iter()
+                        .chain(choices)
+                        .map(|s| editor_quote(s))
+                        .join(" "),
+                )
+            })
+            .join(" ")
+    }
+}
+
+/// Resolve the snippet variables that do not depend on the editor's selections.
+pub fn variables(meta: &EditorMeta, ctx: &Context) -> impl Fn(&str) -> Option<String> {
+    let buffile = meta.buffile.clone();
+    let root = ctx.main_root(meta).clone();
+    move |name| {
+        let path = Path::new(&buffile);
+        let file_name = || path.file_name().map(|s| s.to_string_lossy().into_owned());
+        let value = match name {
+            "TM_FILENAME" => file_name()?,
+            "TM_FILENAME_BASE" => path.file_stem()?.to_string_lossy().into_owned(),
+            "TM_DIRECTORY" => path.parent()?.to_string_lossy().into_owned(),
+            "TM_FILEPATH" => buffile.clone(),
+            "RELATIVE_FILEPATH" => short_file_path(&buffile, &root).to_string(),
+            "WORKSPACE_NAME" => Path::new(&root).file_name()?.to_string_lossy().into_owned(),
+            "WORKSPACE_FOLDER" => root.clone(),
+            // The selections, line and clipboard are not known when the snippet is expanded.
+            "TM_SELECTED_TEXT" | "TM_CURRENT_LINE" | "TM_CURRENT_WORD" | "TM_LINE_INDEX"
+            | "TM_LINE_NUMBER" | "CLIPBOARD" => String::new(),
+            "RANDOM" => format!("{:06}", rand::thread_rng().gen_range(0..1_000_000)),
+            "RANDOM_HEX" => format!("{:06x}", rand::thread_rng().gen_range(0..0x1000000)),
+            "UUID" => {
+                let bytes: [u8; 16] = rand::thread_rng().gen();
+                let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+                // Version 4, variant 1.
+                format!(
+                    "{}-{}-4{}-{:x}{}-{}",
+                    &hex[0..8],
+                    &hex[8..12],
+                    &hex[13..16],
+                    8 | (bytes[8] & 3),
+                    &hex[17..20],
+                    &hex[20..32]
+                )
+            }
+            "CURRENT_SECONDS_UNIX" => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()?
+                .as_secs()
+                .to_string(),
+            _ => current_time_variable(name)?,
+        };
+        Some(value)
+    }
+}
+
+fn current_time_variable(name: &str) -> Option<String> {
+    const MONTHS: [&str; 12] = [
+        "January",
+        "February",
+        "March",
+        "April",
+        "May",
+        "June",
+        "July",
+        "August",
+        "September",
+        "October",
+        "November",
+        "December",
+    ];
+    const DAYS: [&str; 7] = [
+        "Sunday",
+        "Monday",
+        "Tuesday",
+        "Wednesday",
+        "Thursday",
+        "Friday",
+        "Saturday",
+    ];
+    if !name.starts_with("CURRENT_") {
+        return None;
+    }
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&now, &mut tm).is_null() {
+            return None;
+        }
+        tm
+    };
+    let year = tm.tm_year + 1900;
+    let month = tm.tm_mon as usize;
+    let day = tm.tm_wday as usize;
+    Some(match name {
+        "CURRENT_YEAR" => year.to_string(),
+        "CURRENT_YEAR_SHORT" => format!("{:02}", year % 100),
+        "CURRENT_MONTH" => format!("{:02}", month + 1),
+        "CURRENT_MONTH_NAME" => MONTHS[month].to_string(),
+        "CURRENT_MONTH_NAME_SHORT" => MONTHS[month][..3].to_string(),
+        "CURRENT_DATE" => format!("{:02}", tm.tm_mday),
+        "CURRENT_DAY_NAME" => DAYS[day].to_string(),
+        "CURRENT_DAY_NAME_SHORT" => DAYS[day][..3].to_string(),
+        "CURRENT_HOUR" => format!("{:02}", tm.tm_hour),
+        "CURRENT_MINUTE" => format!("{:02}", tm.tm_min),
+        "CURRENT_SECOND" => format!("{:02}", tm.tm_sec),
+        "CURRENT_TIMEZONE_OFFSET" => {
+            let offset = tm.tm_gmtoff / 60;
+            let sign = if offset < 0 { '-' } else { '+' };
+            format!("{sign}{:02}:{:02}", offset.abs() / 60, offset.abs() % 60)
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_nested_placeholders_choices_variables_and_transforms() {
+        let variables = |name: &str| match name {
+            "TM_FILENAME" => Some("my_module.rs".to_string()),
+            "TM_SELECTED_TEXT" => Some(String::new()),
+            _ => None,
+        };
+        let expansion = expand(
+            r"fn ${1:name}(${2:x: ${3:i32}}) -> ${4|u8,u16|} { $1 ${TM_SELECTED_TEXT:todo!()} } \$ // ${TM_FILENAME/(\w+)_(\w+)\..*/${1:/upcase}${2:+ and }$2/} ${UNKNOWN}",
+            &variables,
+        );
+        assert_eq!(
+            expansion.text(),
+            "fn name(x: i32) -> u8 { name todo!() } $ // MY and module UNKNOWN"
+        );
+        assert_eq!(
+            expansion.editor_snippet(),
+            r"fn ${1:name}(${2:x: i32}) -> ${3:u8} { ${1:name} todo!() \} \$ // MY and module UNKNOWN$0"
+        );
+        assert_eq!(expansion.editor_choices(), "2 2 u8 u16");
+    }
+}
@@ -1,4 +1,5 @@
 use crate::context::*;
+use crate::edit_journal::EditJournal;
 use crate::editor_transport::ToEditorSender;
 use crate::position::*;
 use crate::types::*;
@@ -14,6 +15,7 @@ use ropey::Rope;
 use std::borrow::Cow;
 use std::fs::{self, File};
 use std::io::{BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::os::unix::io::FromRawFd;
 
 pub trait TextEditish<T: TextEditish<T>> {
@@ -77,7 +79,7 @@ pub fn apply_text_edits<T: TextEditish<T>>(
     ctx: &mut Context,
 ) {
     let mut command = String::new();
-    apply_text_edits_try_deferred(&mut command, server_id, &meta, uri, edits, ctx);
+    let _ = apply_text_edits_try_deferred(&mut command, None, server_id, &meta, uri, edits, ctx);
     if !command.is_empty() {
         ctx.exec(meta, command);
     }
@@ -85,14 +87,17 @@ pub fn apply_text_edits<T: TextEditish<T>>(
 
 /// Apply text edits to the file pointed by uri either by asking Kakoune to modify corresponding
 /// buffer or by editing file directly when it's not open in editor.
+/// Edits to files on disk are recorded in the journal, if any. Errors are also reported to the
+/// editor.
 pub fn apply_text_edits_try_deferred<T: TextEditish<T>>(
     command: &mut String,
+    journal: Option<&mut EditJournal>,
     server_id: ServerId,
     meta: &EditorMeta,
     uri: Uri,
     edits: Vec<T>,
     ctx: &mut Context,
-) {
+) -> std::io::Result<()> {
     let buffile = uri_to_file_path(&uri);
     let buffile = buffile.to_str().unwrap();
     if let Some(document) = ctx.documents.get(buffile) {
@@ -116,16 +121,20 @@ pub fn apply_text_edits_try_deferred<T: TextEditish<T>>(
             }
             command.push_str(&cmd);
         }
-    } else if let Err(e) = apply_text_edits_to_file(server_id, &uri, edits, &meta.language_id, ctx)
+    } else if let Err(e) =
+        apply_text_edits_to_file(journal, server_id, &uri, edits, &meta.language_id, ctx)
     {
         error!(
             ctx.to_editor(),
             "Failed to apply edits to file {} ({})", buffile, e
         );
+        return Err(e);
     }
+    Ok(())
 }
 
 pub fn apply_text_edits_to_file<T: TextEditish<T>>(
+    journal: Option<&mut EditJournal>,
     server_id: ServerId,
     uri: &Uri,
     text_edits: Vec<T>,
@@ -150,6 +159,7 @@ pub fn apply_text_edits_to_file<T: TextEditish<T>>(
 
     let file = File::open(filename)?;
     let text = Rope::from_reader(BufReader::new(file))?;
+    let original = text.clone();
 
     let (temp_path, mut temp_file) = {
         let template = format!("{}.XXXXXX", filename);
@@ -184,7 +194,15 @@ pub fn apply_text_edits_to_file<T: TextEditish<T>>(
                     text: String::from_utf8_lossy(&updated_text).to_string(),
                 },
             };
-            ctx.notify::<DidOpenTextDocument>(server_id, params);
+            match journal {
+                Some(journal) => journal.edited(
+                    server_id,
+                    original.to_string().into_bytes(),
+                    fs::Permissions::from_mode(stat.st_mode as _),
+                    params,
+                ),
+                None => ctx.notify::<DidOpenTextDocument>(server_id, params),
+            }
             Ok(())
         }
         Err(e) => {
@@ -194,7 +212,7 @@ pub fn apply_text_edits_to_file<T: TextEditish<T>>(
     }
 }
 
-fn apply_text_edits_to_rope<T: TextEditish<T>>(
+pub fn apply_text_edits_to_rope<T: TextEditish<T>>(
     text: Rope,
     text_edits: Vec<T>,
     offset_encoding: OffsetEncoding,
@@ -539,7 +557,7 @@ fn lsp_text_edit_to_kakoune<T: TextEditish<T>>(
     }
 }
 
-fn minimal_edit_sequence<T: TextEditish<T>>(old: &Rope, new: &Rope) -> Vec<T> {
+pub fn minimal_edit_sequence<T: TextEditish<T>>(old: &Rope, new: &Rope) -> Vec<T> {
     let oldv = old.lines().collect::<Vec<_>>();
     let newv = new.lines().collect::<Vec<_>>();
     struct BuildEditScript<'a, T: TextEditish<T>> {
@@ -4,14 +4,20 @@ use std::{
     time::Duration,
 };
 
+use crate::diagnostics::text_document_diagnostic;
+use crate::language_features::on_type_formatting;
+use crate::position::char_index_to_lsp_position;
+use crate::text_edit::{apply_text_edits_to_buffer, minimal_edit_sequence};
 use crate::thread_worker::Worker;
 use crate::types::*;
 use crate::util::file_path_to_uri;
 use crate::util::uri_to_file_path;
+use crate::workspace;
 use crate::{context::*, editor_transport::ToEditorSender};
 use crossbeam_channel::{Receiver, Sender};
 use jsonrpc_core::Value;
 use lsp_types::notification::*;
+use lsp_types::request::WillSaveWaitUntil;
 use lsp_types::*;
 use notify_debouncer_full::{
     new_debouncer,
@@ -43,6 +49,8 @@ pub fn text_document_did_open(
     for &server_id in &meta.servers {
         ctx.notify::<DidOpenTextDocument>(server_id, params.clone());
     }
+    on_type_formatting::install_trigger_hook(&meta, ctx);
+    text_document_diagnostic(meta, ctx);
 }
 
 pub fn text_document_did_change(
@@ -66,27 +74,111 @@ pub fn text_document_did_change(
     };
 
     // Resets metadata for buffer.
-    ctx.documents.insert(meta.buffile.clone(), document);
+    let old_document = ctx.documents.insert(meta.buffile.clone(), document);
     ctx.diagnostics.insert(meta.buffile.clone(), Vec::new());
 
-    let req_params = DidChangeTextDocumentParams {
-        text_document: VersionedTextDocumentIdentifier {
-            uri,
-            version: meta.version,
-        },
-        content_changes: vec![TextDocumentContentChangeEvent {
-            range: None,
-            range_length: None,
-            text: params.draft,
-        }],
+    let text_document = VersionedTextDocumentIdentifier {
+        uri,
+        version: meta.version,
     };
     for &server_id in &meta.servers {
-        ctx.notify::<DidChangeTextDocument>(server_id, req_params.clone());
+        let server = ctx.server(server_id);
+        let content_changes = match &old_document {
+            Some(old_document)
+                if text_document_sync_kind(server) == TextDocumentSyncKind::INCREMENTAL =>
+            {
+                incremental_content_changes(
+                    &old_document.text,
+                    &ctx.documents[&meta.buffile].text,
+                    server.offset_encoding,
+                )
+            }
+            _ => vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: params.draft.clone(),
+            }],
+        };
+        let req_params = DidChangeTextDocumentParams {
+            text_document: text_document.clone(),
+            content_changes,
+        };
+        ctx.notify::<DidChangeTextDocument>(server_id, req_params);
     }
+    text_document_diagnostic(meta, ctx);
+}
+
+fn text_document_sync_kind(server: &ServerSettings) -> TextDocumentSyncKind {
+    match &server.capabilities.as_ref().unwrap().text_document_sync {
+        Some(TextDocumentSyncCapability::Kind(kind)) => *kind,
+        Some(TextDocumentSyncCapability::Options(TextDocumentSyncOptions {
+            change: Some(kind),
+            ..
+        })) => *kind,
+        _ => TextDocumentSyncKind::FULL,
+    }
+}
+
+/// Compute the changes that turn the old buffer contents into the new ones.
+///
+/// The changes are ordered from the end of the document to the start, so each range is valid
+/// in the document produced by applying the preceding changes.
+pub fn incremental_content_changes(
+    old: &Rope,
+    new: &Rope,
+    offset_encoding: OffsetEncoding,
+) -> Vec<TextDocumentContentChangeEvent> {
+    let mut line_edits: Vec<TextEdit> = vec![];
+    // The edit script reports a replacement as a deletion followed by an insertion, so join
+    // adjacent edits before narrowing them down.
+    for edit in minimal_edit_sequence::<TextEdit>(old, new) {
+        match line_edits.last_mut() {
+            Some(last) if last.range.end == edit.range.start => {
+                last.range.end = edit.range.end;
+                last.new_text += &edit.new_text;
+            }
+            _ => line_edits.push(edit),
+        }
+    }
+    let mut changes: Vec<_> = line_edits
+        .into_iter()
+        .map(|TextEdit { range, new_text }| {
+            // Shrink each edit to the characters that actually changed.
+            let mut start = old.line_to_char(range.start.line as _);
+            let mut end = old.line_to_char(range.end.line as _);
+            let new_chars: Vec<char> = new_text.chars().collect();
+            let mut new_start = 0;
+            let mut new_end = new_chars.len();
+            while start < end && new_start < new_end && old.char(start) == new_chars[new_start] {
+                start += 1;
+                new_start += 1;
+            }
+            while start < end && new_start < new_end && old.char(end - 1) == new_chars[new_end - 1]
+            {
+                end -= 1;
+                new_end -= 1;
+            }
+            TextDocumentContentChangeEvent {
+                range: Some(Range {
+                    start: char_index_to_lsp_position(start, old, offset_encoding),
+                    end: char_index_to_lsp_position(end, old, offset_encoding),
+                }),
+                range_length: None,
+                text: new_chars[new_start..new_end].iter().collect(),
+            }
+        })
+        .collect();
+    changes.reverse();
+    changes
 }
 
 pub fn text_document_did_close(meta: EditorMeta, ctx: &mut Context) {
     ctx.documents.remove(&meta.buffile);
+    ctx.semantic_tokens.remove(&meta.buffile);
+    ctx.inlay_hints.remove(&meta.buffile);
+    ctx.inlay_hint_lines.remove(&meta.buffile);
+    ctx.diagnostic_reports
+        .retain(|(_, buffile), _| buffile != &meta.buffile);
     let uri = file_path_to_uri(&meta.buffile);
     let params = DidCloseTextDocumentParams {
         text_document: TextDocumentIdentifier { uri },
@@ -94,6 +186,110 @@ pub fn text_document_did_close(meta: EditorMeta, ctx: &mut Context) {
     for &server_id in &meta.servers {
         ctx.notify::<DidCloseTextDocument>(server_id, params.clone());
     }
+    let Some(roots) = ctx.buffer_roots.remove(&meta.buffile) else {
+        return;
+    };
+    for (server_id, root) in roots {
+        if !ctx
+            .buffer_roots
+            .values()
+            .any(|roots| roots.contains(&(server_id, root.clone())))
+        {
+            workspace::remove_workspace_folder(server_id, &root, ctx);
+        }
+    }
+}
+
+/// Notify the servers that the buffer is about to be written.
+pub fn text_document_will_save(meta: EditorMeta, ctx: &mut Context) {
+    let params = WillSaveTextDocumentParams {
+        text_document: TextDocumentIdentifier {
+            uri: file_path_to_uri(&meta.buffile),
+        },
+        reason: TextDocumentSaveReason::MANUAL,
+    };
+    for &server_id in &meta.servers {
+        if will_save_options(ctx.server(server_id))
+            .is_some_and(|options| options.will_save == Some(true))
+        {
+            ctx.notify::<WillSaveTextDocument>(server_id, params.clone());
+        }
+    }
+}
+
+/// Apply the edits that the servers request before the buffer is written. The editor waits
+/// for them until the timeout of the response FIFO expires; later edits are dropped.
+pub fn text_document_will_save_wait_until(
+    meta: EditorMeta,
+    params: TextDocumentWillSaveWaitUntilParams,
+    ctx: &mut Context,
+) {
+    let req_params: HashMap<_, _> = meta
+        .servers
+        .iter()
+        .filter(|server_id| {
+            will_save_options(ctx.server(**server_id))
+                .is_some_and(|options| options.will_save_wait_until == Some(true))
+        })
+        .map(|&server_id| {
+            (
+                server_id,
+                vec![WillSaveTextDocumentParams {
+                    text_document: TextDocumentIdentifier {
+                        uri: file_path_to_uri(&meta.buffile),
+                    },
+                    reason: TextDocumentSaveReason::MANUAL,
+                }],
+            )
+        })
+        .collect();
+    if req_params.is_empty() {
+        return;
+    }
+
+    ctx.call::<WillSaveWaitUntil, _>(
+        meta,
+        RequestParams::Each(req_params),
+        move |ctx, meta, results| {
+            let Some(response_fifo) = params.response_fifo.take() else {
+                warn!(
+                    ctx.to_editor(),
+                    "Ignoring willSaveWaitUntil edits received after the buffer was written"
+                );
+                return;
+            };
+            // Edits of different servers would conflict, so only the first server's are applied.
+            let Some((server_id, edits)) = results
+                .into_iter()
+                .filter_map(|(server_id, edits)| Some((server_id, edits?)))
+                .find(|(_, edits)| !edits.is_empty())
+            else {
+                return;
+            };
+            let server = ctx.server(server_id);
+            let Some(command) = ctx.documents.get(&meta.buffile).and_then(|document| {
+                apply_text_edits_to_buffer(
+                    ctx.to_editor(),
+                    &meta.client,
+                    None,
+                    edits,
+                    &document.text,
+                    server.offset_encoding,
+                    false,
+                )
+            }) else {
+                return;
+            };
+            ctx.exec_fifo(meta, Some(response_fifo), command);
+        },
+    );
+}
+
+fn will_save_options(server: &ServerSettings) -> Option<&TextDocumentSyncOptions> {
+    match server.capabilities.as_ref()?.text_document_sync.as_ref()? {
+        TextDocumentSyncCapability::Options(options) => Some(options),
+        TextDocumentSyncCapability::Kind(_) => None,
+    }
 }
 
 pub fn text_document_did_save(meta: EditorMeta, ctx: &mut Context) {
@@ -123,6 +319,7 @@ pub fn text_document_did_save(meta: EditorMeta, ctx: &mut Context) {
         };
         ctx.notify::<DidSaveTextDocument>(server_id, params);
     }
+    text_document_diagnostic(meta, ctx);
 }
 
 pub fn spawn_file_watcher(
@@ -314,3 +511,56 @@ pub fn register_workspace_did_change_watched_files(
             .push(CompiledFileSystemWatcher { kind, pattern });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_changes(text: &str, changes: Vec<TextDocumentContentChangeEvent>) -> String {
+        let mut text = Rope::from_str(text);
+        for change in changes {
+            let range = change.range.unwrap();
+            let to_char = |position: Position| {
+                let line = text.line(position.line as _);
+                text.line_to_char(position.line as _)
+                    + line.utf16_cu_to_char(position.character as _)
+            };
+            let (start, end) = (to_char(range.start), to_char(range.end));
+            text.remove(start..end);
+            text.insert(start, &change.text);
+        }
+        text.to_string()
+    }
+
+    #[test]
+    fn incremental_content_changes_roundtrip() {
+        let old = "fn main() {\n    let x = \"§§\";\n    foo(x);\n}\n";
+        let new = "fn main() {\n    let xy = \"§§§\";\n\n    foo(xy);\n}\n";
+        let changes = incremental_content_changes(
+            &Rope::from_str(old),
+            &Rope::from_str(new),
+            OffsetEncoding::Utf16,
+        );
+        assert_eq!(apply_changes(old, changes), new);
+    }
+
+    #[test]
+    fn incremental_content_changes_single_character() {
+        let old = "abc\ndef\n";
+        let new = "abc\ndxef\n";
+        let changes = incremental_content_changes(
+            &Rope::from_str(old),
+            &Rope::from_str(new),
+            OffsetEncoding::Utf8,
+        );
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].text, "x");
+        assert_eq!(
+            changes[0].range,
+            Some(Range {
+                start: Position::new(1, 1),
+                end: Position::new(1, 1),
+            })
+        );
+    }
+}
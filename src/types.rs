@@ -13,6 +13,7 @@ use std::fmt::Display;
 use std::io::{Error, Write};
 use std::ops::Deref;
 use std::os::unix::fs::OpenOptionsExt;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{fs, io};
 
@@ -41,6 +42,8 @@ pub struct Config {
     pub snippet_support: bool,
     #[serde(default)]
     pub file_watch_support: bool,
+    #[serde(default)]
+    pub workspace_edit_preview: bool,
     #[deprecated(note = "use EditorMeta::semantic_tokens")]
     #[serde(default)]
     pub semantic_tokens: SemanticTokenConfig,
@@ -95,6 +98,8 @@ pub struct LanguageServerConfig {
     #[allow(dead_code)]
     workaround_server_sends_plaintext_labeled_as_markdown: Option<bool>,
     pub workaround_eslint: Option<bool>,
+    pub on_type_formatting: Option<bool>,
+    pub completion_priority: Option<i32>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -294,6 +299,31 @@ impl ResponseFifo {
     }
 }
 
+/// A response FIFO that is answered with "nop" when the timeout expires, even if the request
+/// never reaches a handler, for example while servers are initializing.
+#[derive(Clone, Debug)]
+pub struct TimedResponseFifo(Arc<Mutex<Option<ResponseFifo>>>);
+
+impl TimedResponseFifo {
+    pub fn new(fifo: ResponseFifo, timeout: Duration) -> Self {
+        let fifo = Arc::new(Mutex::new(Some(fifo)));
+        // If the request is dropped first, the FIFO is answered right away.
+        let weak = Arc::downgrade(&fifo);
+        std::thread::spawn(move || {
+            std::thread::sleep(timeout);
+            if let Some(fifo) = weak.upgrade() {
+                drop(fifo.lock().unwrap().take());
+            }
+        });
+        Self(fifo)
+    }
+
+    /// The FIFO, unless the timeout has expired.
+    pub fn take(&self) -> Option<ResponseFifo> {
+        self.0.lock().unwrap().take()
+    }
+}
+
 impl Drop for ResponseFifo {
     fn drop(&mut self) {
         if self.0.is_some() {
@@ -392,6 +422,10 @@ pub type ServerId = usize;
 #[derive(Debug)]
 pub struct EditorCompletion {
     pub offset: u32,
+    // Whether to use the replace range of insert/replace edits.
+    pub replace: bool,
+    // The maximum number of items to show, or 0 for no limit.
+    pub max_items: usize,
 }
 
 #[derive(Debug)]
@@ -399,6 +433,11 @@ pub struct TextDocumentDidOpenParams {
     pub draft: String,
 }
 
+#[derive(Debug)]
+pub struct TextDocumentWillSaveWaitUntilParams {
+    pub response_fifo: TimedResponseFifo,
+}
+
 #[derive(Debug)]
 pub struct TextDocumentDidChangeParams {
     pub draft: String,
@@ -435,6 +474,13 @@ pub struct CallHierarchyParams {
     pub incoming_or_outgoing: bool,
 }
 
+#[derive(Clone, Debug)]
+pub struct TypeHierarchyParams {
+    pub position: KakounePosition,
+    pub supertypes_or_subtypes: bool,
+    pub depth: u32,
+}
+
 #[derive(Clone, Debug)]
 pub enum CodeActionFilter {
     ByKind(Vec<CodeActionKind>),
@@ -460,6 +506,13 @@ pub struct RangeFormattingParams {
     pub ranges: Vec<String>,
 }
 
+#[derive(Clone, Debug)]
+pub struct OnTypeFormattingParams {
+    pub position: KakounePosition,
+    pub ch: String,
+    pub formatting_options: FormattingOptions,
+}
+
 #[derive(Clone, Debug)]
 pub struct NextOrPrevSymbolParams {
     pub position: KakounePosition,
@@ -588,7 +641,7 @@ impl Display for BackwardKakouneRange {
 }
 
 /// Represents how language server interprets LSP's `Position.character`
-#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OffsetEncoding {
     /// UTF-8 code units aka bytes
     #[serde(rename = "utf-8")]
@@ -607,3 +660,51 @@ pub struct LineDiagnostics<'a> {
     pub text_face: &'static str,
     pub text_severity: Option<DiagnosticSeverity>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor_transport::mock_to_editor;
+    use crate::util::mkfifo;
+    use std::io::Read;
+    use std::time::Instant;
+
+    /// Open the FIFO for reading without waiting for a writer.
+    fn fifo_reader() -> (String, fs::File) {
+        let path = mkfifo(&mock_to_editor());
+        let reader = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(O_NONBLOCK)
+            .open(&path)
+            .unwrap();
+        (path, reader)
+    }
+
+    fn read_to_string(reader: &mut fs::File) -> String {
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn timed_response_fifo_is_answered_when_dropped() {
+        let (path, mut reader) = fifo_reader();
+        let start = Instant::now();
+        let fifo = TimedResponseFifo::new(ResponseFifo::new(path.clone()), Duration::from_secs(60));
+        drop(fifo);
+        assert_eq!(read_to_string(&mut reader), "nop");
+        assert!(start.elapsed() < Duration::from_secs(1));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn timed_response_fifo_is_answered_after_the_timeout() {
+        let (path, mut reader) = fifo_reader();
+        let fifo =
+            TimedResponseFifo::new(ResponseFifo::new(path.clone()), Duration::from_millis(10));
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(fifo.take().is_none());
+        assert_eq!(read_to_string(&mut reader), "nop");
+        fs::remove_file(path).unwrap();
+    }
+}
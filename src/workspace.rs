@@ -1,12 +1,14 @@
 use crate::context::*;
 use crate::controller::can_serve;
+use crate::edit_journal::EditJournal;
+use crate::edit_preview;
 use crate::language_features::{document_symbol, rust_analyzer};
 use crate::settings::*;
 use crate::text_edit::apply_text_edits_try_deferred;
 use crate::types::*;
 use crate::util::uri_to_file_path;
 use crate::util::*;
-use jsonrpc_core::Params;
+use jsonrpc_core::{MethodCall, Params};
 use lsp_types::notification::*;
 use lsp_types::request::*;
 use lsp_types::*;
@@ -117,6 +119,66 @@ pub fn configuration(
     Ok(Value::Array(items))
 }
 
+pub fn workspace_folder(root: &RootPath) -> WorkspaceFolder {
+    WorkspaceFolder {
+        uri: file_path_to_uri(root),
+        name: root.clone(),
+    }
+}
+
+/// Whether the server wants workspace/didChangeWorkspaceFolders notifications, either from its
+/// capabilities or from a dynamic registration.
+fn wants_workspace_folder_changes(server: &ServerSettings) -> bool {
+    server
+        .capabilities
+        .as_ref()
+        .and_then(|caps| caps.workspace.as_ref())
+        .and_then(|ws| ws.workspace_folders.as_ref())
+        .and_then(|folders| folders.change_notifications.as_ref())
+        .is_some_and(|notifications| !matches!(notifications, OneOf::Left(false)))
+}
+
+/// Let a running server serve another root.
+pub fn add_workspace_folder(server_id: ServerId, root: &RootPath, ctx: &mut Context) {
+    let server = ctx.language_servers.get_mut(&server_id).unwrap();
+    server.roots.push(root.clone());
+    ctx.route_cache
+        .insert((server.name.clone(), root.clone()), server_id);
+    if !wants_workspace_folder_changes(server) {
+        return;
+    }
+    let params = DidChangeWorkspaceFoldersParams {
+        event: WorkspaceFoldersChangeEvent {
+            added: vec![workspace_folder(root)],
+            removed: vec![],
+        },
+    };
+    ctx.notify::<DidChangeWorkspaceFolders>(server_id, params);
+}
+
+/// Stop serving a root that was added with add_workspace_folder. The root the server was
+/// started with is kept, since it is also the server's root URI.
+pub fn remove_workspace_folder(server_id: ServerId, root: &RootPath, ctx: &mut Context) {
+    let Some(server) = ctx.language_servers.get_mut(&server_id) else {
+        return;
+    };
+    if server.roots.first() == Some(root) || !server.roots.contains(root) {
+        return;
+    }
+    server.roots.retain(|r| r != root);
+    ctx.route_cache.remove(&(server.name.clone(), root.clone()));
+    if !wants_workspace_folder_changes(server) {
+        return;
+    }
+    let params = DidChangeWorkspaceFoldersParams {
+        event: WorkspaceFoldersChangeEvent {
+            added: vec![],
+            removed: vec![workspace_folder(root)],
+        },
+    };
+    ctx.notify::<DidChangeWorkspaceFolders>(server_id, params);
+}
+
 pub fn workspace_symbol(meta: EditorMeta, params: WorkspaceSymbolParams, ctx: &mut Context) {
     ctx.call::<WorkspaceSymbolRequest, _>(
         meta,
@@ -262,7 +324,7 @@ pub fn execute_command(
     }
 }
 
-pub fn apply_document_resource_op(op: ResourceOp) -> io::Result<()> {
+pub fn apply_document_resource_op(op: ResourceOp, journal: &mut EditJournal) -> io::Result<()> {
     match op {
         ResourceOp::Create(op) => {
             let path = uri_to_file_path(&op.uri);
@@ -274,9 +336,8 @@ pub fn apply_document_resource_op(op: ResourceOp) -> io::Result<()> {
             if ignore_if_exists && path.exists() {
                 Ok(())
             } else {
-                if let Some(parent) = path.parent() {
-                    fs::create_dir_all(parent)?;
-                }
+                journal.create_parent_dirs(&path)?;
+                journal.save(&path)?;
                 fs::write(&path, [])
             }
         }
@@ -288,12 +349,14 @@ pub fn apply_document_resource_op(op: ResourceOp) -> io::Result<()> {
                 } else {
                     false
                 };
+                journal.save(&path)?;
                 if recursive {
                     fs::remove_dir_all(&path)
                 } else {
                     fs::remove_dir(&path)
                 }
             } else if path.is_file() {
+                journal.save(&path)?;
                 fs::remove_file(&path)
             } else {
                 Ok(())
@@ -310,16 +373,38 @@ pub fn apply_document_resource_op(op: ResourceOp) -> io::Result<()> {
             if ignore_if_exists && to.exists() {
                 Ok(())
             } else {
-                if let Some(parent) = to.parent() {
-                    fs::create_dir_all(parent)?;
-                }
-                fs::rename(from, &to)
+                journal.create_parent_dirs(&to)?;
+                journal.save(&to)?;
+                fs::rename(&from, &to)?;
+                journal.renamed(from, to);
+                Ok(())
             }
         }
     }
 }
 
-// TODO handle version, so change is not applied if buffer is modified (and need to show a warning)
+/// Find the first versioned document edit whose buffer has been modified since.
+fn stale_document_edit(edit: &WorkspaceEdit, ctx: &Context) -> Option<(String, i32, i32)> {
+    let text_document_edits: Vec<_> = match &edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => edits.iter().collect(),
+        Some(DocumentChanges::Operations(ops)) => ops
+            .iter()
+            .filter_map(|op| match op {
+                DocumentChangeOperation::Edit(edit) => Some(edit),
+                DocumentChangeOperation::Op(_) => None,
+            })
+            .collect(),
+        None => vec![],
+    };
+    text_document_edits.into_iter().find_map(|edit| {
+        let version = edit.text_document.version?;
+        let buffile = uri_to_file_path(&edit.text_document.uri);
+        let buffile = buffile.to_str().unwrap();
+        let document = ctx.documents.get(buffile)?;
+        (document.version != version).then(|| (buffile.to_string(), version, document.version))
+    })
+}
+
 pub fn apply_edit(
     server_id: ServerId,
     meta: EditorMeta,
@@ -327,56 +412,110 @@ pub fn apply_edit(
     edit: WorkspaceEdit,
     ctx: &mut Context,
 ) -> ApplyWorkspaceEditResponse {
+    if let Some((buffile, version, current_version)) = stale_document_edit(&edit, ctx) {
+        let msg = format!(
+            "refusing to apply edit to {}: it was computed for version {} but the buffer is at version {}",
+            short_file_path(&buffile, ctx.main_root(&meta)),
+            version,
+            current_version
+        );
+        ctx.show_error(meta, &msg);
+        return ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some(msg),
+            failed_change: None,
+        };
+    }
     let mut command = String::new();
+    let mut journal = EditJournal::default();
+    let mut failure = None;
     if let Some(document_changes) = edit.document_changes {
         match document_changes {
             DocumentChanges::Edits(edits) => {
-                for edit in edits {
-                    apply_text_edits_try_deferred(
+                for (index, edit) in edits.into_iter().enumerate() {
+                    if let Err(e) = apply_text_edits_try_deferred(
                         &mut command,
+                        Some(&mut journal),
                         server_id,
                         &meta,
                         edit.text_document.uri,
                         edit.edits,
                         ctx,
-                    );
+                    ) {
+                        failure = Some((e.to_string(), Some(index as u32)));
+                        break;
+                    }
                 }
             }
             DocumentChanges::Operations(ops) => {
-                for op in ops {
-                    match op {
-                        DocumentChangeOperation::Edit(edit) => {
-                            apply_text_edits_try_deferred(
-                                &mut command,
-                                server_id,
-                                &meta,
-                                edit.text_document.uri,
-                                edit.edits,
-                                ctx,
-                            );
-                        }
+                for (index, op) in ops.into_iter().enumerate() {
+                    let result = match op {
+                        DocumentChangeOperation::Edit(edit) => apply_text_edits_try_deferred(
+                            &mut command,
+                            Some(&mut journal),
+                            server_id,
+                            &meta,
+                            edit.text_document.uri,
+                            edit.edits,
+                            ctx,
+                        ),
                         DocumentChangeOperation::Op(op) => {
-                            if let Err(e) = apply_document_resource_op(op) {
+                            apply_document_resource_op(op, &mut journal).map_err(|e| {
                                 error!(
                                     ctx.to_editor(),
                                     "failed to apply document change operation: {}", e
                                 );
-                                return ApplyWorkspaceEditResponse {
-                                    applied: false,
-                                    failure_reason: None,
-                                    failed_change: None,
-                                };
-                            }
+                                e
+                            })
                         }
+                    };
+                    if let Err(e) = result {
+                        failure = Some((e.to_string(), Some(index as u32)));
+                        break;
                     }
                 }
             }
         }
     } else if let Some(changes) = edit.changes {
         for (uri, change) in changes {
-            apply_text_edits_try_deferred(&mut command, server_id, &meta, uri, change, ctx);
+            if let Err(e) = apply_text_edits_try_deferred(
+                &mut command,
+                Some(&mut journal),
+                server_id,
+                &meta,
+                uri,
+                change,
+                ctx,
+            ) {
+                failure = Some((e.to_string(), None));
+                break;
+            }
         }
     }
+    if let Some((failure_reason, failed_change)) = failure {
+        // Edits to open buffers have not been sent to the editor yet, so only the on-disk
+        // changes need to be reverted.
+        let failure_reason = match journal.rollback() {
+            Ok(()) => failure_reason,
+            Err(e) => {
+                error!(ctx.to_editor(), "failed to roll back workspace edit: {}", e);
+                format!("{} (rollback failed: {})", failure_reason, e)
+            }
+        };
+        ctx.show_error(
+            meta,
+            format!("failed to apply workspace edit: {}", failure_reason),
+        );
+        return ApplyWorkspaceEditResponse {
+            applied: false,
+            failure_reason: Some(failure_reason),
+            failed_change,
+        };
+    }
+    journal.commit(ctx);
+    if !journal.is_empty() {
+        ctx.workspace_edit_journal = Some(journal);
+    }
     if !command.is_empty() {
         ctx.exec_fifo(meta, response_fifo, command);
     }
@@ -387,6 +526,20 @@ pub fn apply_edit(
     }
 }
 
+pub fn undo_workspace_edit(meta: EditorMeta, ctx: &mut Context) {
+    let Some(journal) = ctx.workspace_edit_journal.take() else {
+        ctx.show_error(meta, "no workspace edit changed files on disk");
+        return;
+    };
+    match journal.undo(ctx) {
+        Ok(()) => ctx.exec(
+            meta,
+            "echo -markup '{Information}Reverted the files changed on disk by the last workspace edit'",
+        ),
+        Err(e) => ctx.show_error(meta, format!("failed to undo workspace edit: {}", e)),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct EditorApplyEdit {
     pub edit: String,
@@ -402,16 +555,98 @@ pub fn apply_edit_from_editor(
     let edit = WorkspaceEdit::deserialize(serde_json::from_str::<Value>(&params.edit).unwrap())
         .expect("Failed to parse edit");
 
-    apply_edit(server_id, meta, response_fifo, edit, ctx);
+    edit_preview::apply_or_preview_edit(server_id, meta, response_fifo, None, edit, None, ctx);
 }
 
+/// Apply an edit requested by the server. The reply is deferred while the edit is previewed.
 pub fn apply_edit_from_server(
     meta: EditorMeta,
     server_id: ServerId,
-    params: Params,
+    request: MethodCall,
     ctx: &mut Context,
-) -> Result<Value, jsonrpc_core::Error> {
-    let params: ApplyWorkspaceEditParams = params.parse()?;
-    let response = apply_edit(server_id, meta, None, params.edit, ctx);
-    Ok(serde_json::to_value(response).unwrap())
+) {
+    let params: ApplyWorkspaceEditParams = match request.params.parse() {
+        Ok(params) => params,
+        Err(e) => {
+            ctx.reply(server_id, request.id, Err(e));
+            return;
+        }
+    };
+    let response = edit_preview::apply_or_preview_edit(
+        server_id,
+        meta,
+        None,
+        Some(request.id.clone()),
+        params.edit,
+        None,
+        ctx,
+    );
+    if let Some(response) = response {
+        ctx.reply(
+            server_id,
+            request.id,
+            Ok(serde_json::to_value(response).unwrap()),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor_transport::mock_to_editor;
+    use crate::language_server_transport;
+    use std::collections::HashMap;
+
+    #[test]
+    fn workspace_folders_keep_roots_and_routes_consistent() {
+        let mut ctx = Context::new(
+            SessionId("session".to_string()),
+            mock_to_editor(),
+            Config::default(),
+        );
+        let server_id = ServerId::default();
+        let name = "server".to_string();
+        let transport = language_server_transport::start(
+            ctx.to_editor(),
+            name.clone(),
+            "cat",
+            &[],
+            &HashMap::new(),
+        )
+        .unwrap();
+        ctx.language_servers.insert(
+            server_id,
+            ServerSettings {
+                name: name.clone(),
+                roots: vec!["/first".to_string()],
+                single_instance: None,
+                offset_encoding: Default::default(),
+                preferred_offset_encoding: None,
+                transport,
+                capabilities: None,
+                settings: None,
+                workaround_eslint: false,
+            },
+        );
+        ctx.route_cache
+            .insert((name.clone(), "/first".to_string()), server_id);
+
+        let second = "/second".to_string();
+        add_workspace_folder(server_id, &second, &mut ctx);
+        assert_eq!(ctx.server(server_id).roots, ["/first", "/second"]);
+        assert_eq!(
+            ctx.route_cache.get(&(name.clone(), second.clone())),
+            Some(&server_id)
+        );
+
+        // The initial root can't be removed.
+        remove_workspace_folder(server_id, &"/first".to_string(), &mut ctx);
+        remove_workspace_folder(server_id, &second, &mut ctx);
+        assert_eq!(ctx.server(server_id).roots, ["/first"]);
+        assert!(!ctx.route_cache.contains_key(&(name.clone(), second)));
+        assert_eq!(
+            ctx.route_cache.get(&(name, "/first".to_string())),
+            Some(&server_id)
+        );
+    }
 }